@@ -0,0 +1,81 @@
+//! 評定グリッドの読み取りを，1マスずつ切り出す方法 ([is_this_selected]) と
+//! 全体を一度に切り出す方法 ([GridReader]) とで比べる．
+//! 合成したフレーム（真っ黒な画面に1マスだけ白く塗ったもの）を使うので動画はいらない．
+//! `cargo bench --bench grid_readout`
+
+use std::time::Instant;
+
+use ikfm2502timeit::base::Sq;
+use ikfm2502timeit::follow_clicks::{is_this_selected, GridReader, SELECTED_BRIGHTNESS};
+use ikfm2502timeit::layout::Layout;
+use opencv::core::{Mat, Scalar, CV_8UC3};
+use opencv::imgproc::{rectangle, FILLED, LINE_8};
+use opencv::prelude::*;
+
+const N_FRAMES: usize = 200;
+
+fn synthetic_frames(layout: &Layout) -> opencv::Result<Vec<Mat>> {
+    let n = layout.grid.num as i32 + 1;
+    (0..N_FRAMES)
+        .map(|i| {
+            let mut frame = Mat::new_rows_cols_with_default(
+                layout.ref_height,
+                layout.ref_width,
+                CV_8UC3,
+                Scalar::all(0.0),
+            )?;
+            let (x, y) = (i as i32 % n, i as i32 / n % n);
+            rectangle(
+                &mut frame,
+                Sq::grid_at(&layout.grid, x, y).into_rect(),
+                Scalar::all(255.0),
+                FILLED,
+                LINE_8,
+                0,
+            )?;
+            Ok(frame)
+        })
+        .collect()
+}
+
+fn main() -> opencv::Result<()> {
+    let layout = Layout::default();
+    let grid = layout.grid;
+    let frames = synthetic_frames(&layout)?;
+    let n = grid.num as i32;
+
+    let start = Instant::now();
+    let per_cell: Vec<Vec<bool>> = frames
+        .iter()
+        .map(|frame| {
+            (0..=n)
+                .flat_map(|x| (0..=n).map(move |y| is_this_selected(frame, &grid, x, y)))
+                .collect::<opencv::Result<_>>()
+        })
+        .collect::<opencv::Result<_>>()?;
+    let per_cell_time = start.elapsed();
+
+    let reader = GridReader::new(&grid);
+    let start = Instant::now();
+    let single_pass: Vec<Vec<bool>> = frames
+        .iter()
+        .map(|frame| {
+            Ok(reader
+                .cell_means(frame)?
+                .iter()
+                .map(|&mean| mean > SELECTED_BRIGHTNESS)
+                .collect())
+        })
+        .collect::<opencv::Result<_>>()?;
+    let single_pass_time = start.elapsed();
+
+    assert_eq!(per_cell, single_pass, "the two readouts disagree");
+    println!("{N_FRAMES} frames");
+    println!("per cell:    {per_cell_time:?}");
+    println!("single pass: {single_pass_time:?}");
+    println!(
+        "speedup:     {:.1}x",
+        per_cell_time.as_secs_f64() / single_pass_time.as_secs_f64()
+    );
+    Ok(())
+}
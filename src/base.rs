@@ -1,5 +1,74 @@
+use opencv::core::{Rect, Size};
+use opencv::imgproc::{resize, InterpolationFlags};
+use opencv::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::layout::GridLayout;
+
 pub type Frame = usize;
 
+//      x:0   1  ....
+//   y: ┌───┬───┐
+//   0  │   │   │
+//      ├───┼───┼
+//   1  │   │   │
+//      ├───┼───┼
+//   2  │   │   │
+//      ├───┴───┴
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// 見たまま，（画像の中の）長方形領域．
+pub struct Sq {
+    pub x: i32,
+    pub y: i32,
+    pub w: i32,
+    pub h: i32,
+}
+
+/// 上の数え方で (x,y) のグリッドの中心部の座標を得る
+/// [0, 8]
+impl Sq {
+    pub fn grid_at(grid: &GridLayout, x: i32, y: i32) -> Self {
+        Sq {
+            x: grid.topleft_x + grid.len * x + grid.padding,
+            y: grid.topleft_y + grid.len * y + grid.padding,
+            w: grid.centre_size,
+            h: grid.centre_size,
+        }
+    }
+    pub fn into_rect(self) -> Rect {
+        Rect {
+            x: self.x,
+            y: self.y,
+            width: self.w,
+            height: self.h,
+        }
+    }
+}
+
+/// 画像を sq と同じ大きさにする．同じならそのまま返す．
+/// 解像度の違う動画に参照画像を合わせるのに使う
+pub fn fit_to(img: Mat, sq: &Sq) -> opencv::Result<Mat> {
+    if img.cols() == sq.w && img.rows() == sq.h {
+        return Ok(img);
+    }
+    let interpolation = if img.cols() > sq.w {
+        InterpolationFlags::INTER_AREA
+    } else {
+        InterpolationFlags::INTER_LINEAR
+    };
+    let mut resized = Mat::default();
+    resize(
+        &img,
+        &mut resized,
+        Size::new(sq.w, sq.h),
+        0.0,
+        0.0,
+        interpolation as i32,
+    )?;
+    Ok(resized)
+}
+
 pub fn group_by<T, U, F>(it: &[T], f: F) -> Vec<Vec<T>>
 where
     T: Clone,
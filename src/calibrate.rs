@@ -0,0 +1,238 @@
+use opencv::core::{count_non_zero, Point, Rect, Scalar, Vector};
+use opencv::imgcodecs::imwrite;
+use opencv::imgproc::{
+    cvt_color_def, line, rectangle, threshold, ColorConversionCodes, ThresholdTypes, LINE_8,
+};
+use opencv::prelude::*;
+use opencv::videoio::{VideoCapture, CAP_PROP_POS_FRAMES};
+
+use std::fmt;
+
+use crate::base::Sq;
+use crate::elog;
+use crate::error;
+use crate::layout::{GridLayout, Layout};
+
+/// これより明るいところを白線とみなす
+const LINE_BRIGHTNESS: f64 = 200.0;
+/// 白線の間隔のずれをどこまで許すか (px)．途中で1px ずれたりするので
+const LINE_TOLERANCE: i32 = 2;
+
+#[derive(Debug)]
+/// OpenCVに起因するエラー，指定のフレームが読めない，
+/// 格子状の白線が見つからない (x か y か)
+pub enum CalibrateError {
+    OpenCVError(opencv::error::Error),
+    FrameNotRead,
+    LinesNotFound(&'static str),
+}
+
+impl fmt::Display for CalibrateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalibrateError::OpenCVError(e) => write!(f, "error on opencv: {e}"),
+            CalibrateError::FrameNotRead => write!(f, "cannot read the frame"),
+            CalibrateError::LinesNotFound(axis) => {
+                write!(f, "no equally spaced grid lines along {axis}")
+            }
+        }
+    }
+}
+
+impl From<opencv::error::Error> for CalibrateError {
+    fn from(err: opencv::error::Error) -> CalibrateError {
+        CalibrateError::OpenCVError(err)
+    }
+}
+
+/// 各列（あるいは各行）にある白いピクセルの数
+fn bright_profile(bw: &Mat, vertical: bool) -> opencv::Result<Vec<i32>> {
+    let (n, len) = if vertical {
+        (bw.cols(), bw.rows())
+    } else {
+        (bw.rows(), bw.cols())
+    };
+    (0..n)
+        .map(|i| {
+            let rect = if vertical {
+                Rect::new(i, 0, 1, len)
+            } else {
+                Rect::new(0, i, len, 1)
+            };
+            count_non_zero(&Mat::roi(bw, rect)?)
+        })
+        .collect()
+}
+
+/// 白線の候補．十分明るい列が連続しているところをまとめて，その始まりの位置を返す
+fn line_candidates(profile: &[i32]) -> Vec<i32> {
+    let max = profile.iter().copied().max().unwrap_or(0);
+    if max == 0 {
+        return vec![];
+    }
+    let mut candidates = vec![];
+    let mut in_line = false;
+    for (i, &p) in profile.iter().enumerate() {
+        let is_line = p * 2 >= max;
+        if is_line && !in_line {
+            candidates.push(i as i32);
+        }
+        in_line = is_line;
+    }
+    candidates
+}
+
+/// 候補の中から，等間隔に `n_lines` 本並んでいるものを探す．
+/// 見つかれば (最初の線の位置, 間隔)．
+/// 候補が複数あれば一番広いものをとる（グリッドの中の細かい模様などよりは大きいはず）
+fn find_equally_spaced(candidates: &[i32], n_lines: usize) -> Option<(i32, i32)> {
+    let has_line_near = |pos: i32| {
+        candidates
+            .iter()
+            .any(|&c| (c - pos).abs() <= LINE_TOLERANCE)
+    };
+    let mut best: Option<(i32, i32)> = None;
+    let steps = n_lines as i32 - 1;
+    for &first in candidates {
+        for &last in candidates.iter().filter(|&&c| c > first) {
+            let span = last - first;
+            if span < steps {
+                continue;
+            }
+            let is_grid = (1..steps).all(|k| has_line_near(first + span * k / steps));
+            if is_grid && best.is_none_or(|(_, len)| span / steps > len) {
+                // 途中の 1px ずれを含めて丸める
+                best = Some((first, (span + steps / 2) / steps));
+            }
+        }
+    }
+    best
+}
+
+/// `frame_number` フレーム目から評定グリッドの白線を探し，その位置を `layout.grid` に
+/// 入れたものを返す．`search` が与えられればその範囲の中だけを探す．
+/// padding と centre_size は元の layout との比を保つように拡大縮小する．
+pub fn calibrate(
+    vc: &mut VideoCapture,
+    frame_number: usize,
+    layout: &Layout,
+    search: Option<Sq>,
+) -> Result<(Layout, Mat), CalibrateError> {
+    vc.set(CAP_PROP_POS_FRAMES, frame_number as f64)?;
+    let mut frame = Mat::default();
+    if !vc.read(&mut frame)? {
+        return Err(CalibrateError::FrameNotRead);
+    }
+    let search = search
+        .map(Sq::into_rect)
+        .unwrap_or(Rect::new(0, 0, frame.cols(), frame.rows()));
+    let region = Mat::roi(&frame, search)?;
+    let mut gray = Mat::default();
+    cvt_color_def(
+        &region,
+        &mut gray,
+        ColorConversionCodes::COLOR_BGR2GRAY as i32,
+    )?;
+    let mut bw = Mat::default();
+    threshold(
+        &gray,
+        &mut bw,
+        LINE_BRIGHTNESS,
+        255.0,
+        ThresholdTypes::THRESH_BINARY as i32,
+    )?;
+
+    // num はマスの添字の最大なので，線の本数は num + 2
+    let n_lines = layout.grid.num as usize + 2;
+    let xs = line_candidates(&bright_profile(&bw, true)?);
+    let ys = line_candidates(&bright_profile(&bw, false)?);
+    let (left, len_x) =
+        find_equally_spaced(&xs, n_lines).ok_or(CalibrateError::LinesNotFound("x"))?;
+    let (top, len_y) =
+        find_equally_spaced(&ys, n_lines).ok_or(CalibrateError::LinesNotFound("y"))?;
+    if len_x != len_y {
+        elog!("calibrate:warning: grid is not square ({len_x}px x {len_y}px); using x");
+    }
+    let len = len_x;
+    let old = layout.grid;
+    let grid = GridLayout {
+        topleft_x: search.x + left,
+        topleft_y: search.y + top,
+        len,
+        num: old.num,
+        padding: (old.padding as f64 * len as f64 / old.len as f64).round() as i32,
+        centre_size: (old.centre_size as f64 * len as f64 / old.len as f64).round() as i32,
+    };
+    elog!("calibrate: found grid {grid:?}");
+    // 座標はこの動画のピクセルで書いているので，基準もこの動画の解像度にする
+    let calibrated = Layout {
+        ref_width: frame.cols(),
+        ref_height: frame.rows(),
+        grid,
+        ..*layout
+    };
+    let diagnostic = draw_grid(&frame, &grid)?;
+    Ok((calibrated, diagnostic))
+}
+
+/// 見つけた白線を緑で，実際に見に行くマスの中心部を赤で描いたもの
+fn draw_grid(frame: &Mat, grid: &GridLayout) -> opencv::Result<Mat> {
+    let mut img = frame.try_clone()?;
+    let green = Scalar::new(0.0, 255.0, 0.0, 0.0);
+    let red = Scalar::new(0.0, 0.0, 255.0, 0.0);
+    let cells = grid.num as i32 + 1;
+    let (left, top) = (grid.topleft_x, grid.topleft_y);
+    let (right, bottom) = (left + grid.len * cells, top + grid.len * cells);
+    for k in 0..=cells {
+        let x = left + grid.len * k;
+        let y = top + grid.len * k;
+        line(
+            &mut img,
+            Point::new(x, top),
+            Point::new(x, bottom),
+            green,
+            1,
+            LINE_8,
+            0,
+        )?;
+        line(
+            &mut img,
+            Point::new(left, y),
+            Point::new(right, y),
+            green,
+            1,
+            LINE_8,
+            0,
+        )?;
+    }
+    for x in 0..cells {
+        for y in 0..cells {
+            rectangle(
+                &mut img,
+                Sq::grid_at(grid, x, y).into_rect(),
+                red,
+                2,
+                LINE_8,
+                0,
+            )?;
+        }
+    }
+    Ok(img)
+}
+
+/// [calibrate] の結果を `out` に TOML として，確認用の画像を `out.png` に書き出す
+pub fn do_calibrate(
+    vc: &mut VideoCapture,
+    frame_number: usize,
+    layout: &Layout,
+    search: Option<Sq>,
+    out: &str,
+) -> error::Result<()> {
+    let (calibrated, diagnostic) = calibrate(vc, frame_number, layout, search)?;
+    let toml = toml::to_string(&calibrated).expect("Layout is always serializable");
+    std::fs::write(out, toml)?;
+    let img_file = format!("{out}.png");
+    imwrite(&img_file, &diagnostic, &Vector::new())?;
+    elog!("calibrate: wrote {out} and {img_file}");
+    Ok(())
+}
@@ -1,21 +1,34 @@
+/// 以下の座標はこの解像度で撮った画面でのもの．
+/// 違う解像度の動画では，これとの比で拡大縮小して使う
+pub const REF_WIDTH: i32 = 2880;
+pub const REF_HEIGHT: i32 = 1800;
+
 /// ROI としてとる場所
 pub const VA_ROI_X: i32 = 2550;
 pub const VA_ROI_Y: i32 = 790;
 pub const VA_ROI_W: i32 = 90;
 pub const VA_ROI_H: i32 = 50;
 
-/// 切り出したROIを保存する先
-pub const TEMPL_FILE: &str = "./data/va_roi.png";
+/// 切り出したROI（参照画像）を保存するディレクトリ
+pub const TEMPLATE_DIR: &str = "./data";
+/// --template を与えなかったときの参照画像の名前．`{TEMPLATE_DIR}/va_roi.png` になる
+pub const DEFAULT_TEMPLATE: &str = "va_roi";
 
 /// match_shapes(&tmpl, &roi, *,*) の閾値
 pub const MATCH_SHAPES_THRESHOLD: f64 = 0.05;
 
-/// 2値化して，見比べて，異なる分がこれ以下ならOK
-/// ピクセルってわけでもなさそうなんだけど 255*pix なのかな…？？
-pub const MATCH_BW_THRESHOLD: f64 = 10000.0;
+/// 2値化して，見比べて，マスクの中で異なるピクセルの割合がこれ未満ならOK．
+/// 以前はピクセル数 x255 で 10000 としていた (90x50 の ROI で約39ピクセル)．
+/// マスク無しの 90x50 ならそれと同じだが，マスクがあると分母が小さくなるので
+/// 外れとみなすピクセル数も減る
+pub const MATCH_BW_THRESHOLD: f64 = 10000.0 / 255.0 / 4500.0;
+
+/// 1 - (正規化相互相関) がこれ未満ならOK．つまり相関が 0.8 より上
+pub const MATCH_NCC_THRESHOLD: f64 = 0.2;
 
 /// デフォルトのFPS. 本来はファイルが持ってる情報だが，
-/// 取り直すのが面倒なこともあるので…
+/// 取れないこともあるので…
+/// 普段は各フレームの時刻を使うので，それも取れないときの最後の手段
 pub const DEFAULT_FPS: f64 = 30.0;
 
 /// 評定グリッドの一番左上，白線の交点
@@ -0,0 +1,218 @@
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use opencv::core::{min_max_loc, no_array, Point, Rect};
+use opencv::imgcodecs::{imread, ImreadModes};
+use opencv::imgproc::{cvt_color_def, match_template, ColorConversionCodes, TemplateMatchModes};
+use opencv::prelude::*;
+
+use crate::base::{fit_to, group_by, Frame, Sq};
+use crate::layout::Layout;
+use crate::mask::load_mask;
+use crate::match_bw::FindFramesError;
+use crate::scale::{bounding_box, Cell, ScaleDef};
+use crate::timing::Timing;
+
+/// 相関の一番高いところがこれ未満なら，ポインタは見えていない（グリッドの外など）
+pub const CURSOR_MIN_PEAK: f64 = 0.7;
+
+/// 座標の一つの軸についての，ピクセルからマスの座標への1次式
+#[derive(Debug, Clone, Copy)]
+struct AxisFit {
+    slope: f64,
+    intercept: f64,
+}
+
+impl AxisFit {
+    /// マスの中心 (px) とその座標の組に最小二乗で直線を当てる．
+    /// ピクセルが全部同じ（スライダーの縦など）なら座標の平均
+    fn fit(points: &[(f64, f64)]) -> Self {
+        let n = points.len().max(1) as f64;
+        let mean_p = points.iter().map(|p| p.0).sum::<f64>() / n;
+        let mean_v = points.iter().map(|p| p.1).sum::<f64>() / n;
+        let var: f64 = points.iter().map(|p| (p.0 - mean_p).powi(2)).sum();
+        let cov: f64 = points.iter().map(|p| (p.0 - mean_p) * (p.1 - mean_v)).sum();
+        let slope = if var > 0.0 { cov / var } else { 0.0 };
+        AxisFit {
+            slope,
+            intercept: mean_v - slope * mean_p,
+        }
+    }
+
+    fn at(&self, px: f64) -> f64 {
+        self.slope * px + self.intercept
+    }
+}
+
+/// 評定画面のフレームで，グリッドの上にあるマウスポインタを探す．
+/// ポインタの参照画像は layout の基準の解像度の録画から切り抜いたもので，左上をポインタの先とする．
+/// 解像度の違う動画では他の参照画像と同じく拡大縮小する
+pub struct CursorTracker {
+    tmpl: Mat,
+    /// tmpl のうち相関を取るところ (255)．ポインタの周りの背景を除く
+    mask: Mat,
+    /// 探すところ．全マスを含む長方形を，ポインタの大きさだけ広げたもの
+    search: Rect,
+    fit_x: AxisFit,
+    fit_y: AxisFit,
+}
+
+#[derive(Debug, Clone, Copy)]
+/// 見つかったポインタの先の位置
+pub struct CursorPos {
+    /// フレームの中の位置 (px)
+    pub px: i32,
+    pub py: i32,
+    /// マスの座標に直したもの（連続値）
+    pub x: f64,
+    pub y: f64,
+    /// 相関 (-1 から 1)
+    pub peak: f64,
+}
+
+impl CursorTracker {
+    /// cells は width x height の動画での回答のマスの一覧 ([ScaleDef::cells])．
+    /// base は動画の解像度に合わせる前の layout
+    pub fn from_file(
+        f: &str,
+        cells: &[Cell],
+        base: &Layout,
+        width: i32,
+        height: i32,
+    ) -> Result<Self, FindFramesError> {
+        let tmpl = imread(f, ImreadModes::IMREAD_GRAYSCALE as i32)?;
+        if tmpl.empty() {
+            return Err(FindFramesError::TemplateNotFound);
+        }
+        let sx = width as f64 / base.ref_width as f64;
+        let sy = height as f64 / base.ref_height as f64;
+        let size = Sq {
+            x: 0,
+            y: 0,
+            w: ((tmpl.cols() as f64 * sx).round() as i32).max(1),
+            h: ((tmpl.rows() as f64 * sy).round() as i32).max(1),
+        };
+        let tmpl = fit_to(tmpl, &size)?;
+        let bbox = bounding_box(cells);
+        let centres = |c: &Cell| {
+            (
+                c.rect.x as f64 + c.rect.w as f64 / 2.0,
+                c.rect.y as f64 + c.rect.h as f64 / 2.0,
+            )
+        };
+        let xs: Vec<(f64, f64)> = cells
+            .iter()
+            .map(|c| (centres(c).0, c.loc.x as f64))
+            .collect();
+        let ys: Vec<(f64, f64)> = cells
+            .iter()
+            .map(|c| (centres(c).1, c.loc.y as f64))
+            .collect();
+        Ok(CursorTracker {
+            mask: load_mask(f, &size)?,
+            search: Rect::new(
+                bbox.x - size.w,
+                bbox.y - size.h,
+                bbox.w + 2 * size.w,
+                bbox.h + 2 * size.h,
+            ),
+            tmpl,
+            fit_x: AxisFit::fit(&xs),
+            fit_y: AxisFit::fit(&ys),
+        })
+    }
+
+    /// search をフレームからはみ出さないように削ったもの
+    fn search_in(&self, frame: &Mat) -> Rect {
+        let left = self.search.x.max(0);
+        let top = self.search.y.max(0);
+        let right = (self.search.x + self.search.width).min(frame.cols());
+        let bottom = (self.search.y + self.search.height).min(frame.rows());
+        Rect::new(left, top, right - left, bottom - top)
+    }
+
+    /// ポインタの位置．見えていなければ None
+    pub fn locate(&self, frame: &Mat) -> opencv::Result<Option<CursorPos>> {
+        let search = self.search_in(frame);
+        if search.width < self.tmpl.cols() || search.height < self.tmpl.rows() {
+            return Ok(None);
+        }
+        let window = Mat::roi(frame, search)?;
+        let mut gs_window = Mat::default();
+        cvt_color_def(
+            &window,
+            &mut gs_window,
+            ColorConversionCodes::COLOR_BGR2GRAY as i32,
+        )?;
+        let mut result = Mat::default();
+        match_template(
+            &gs_window,
+            &self.tmpl,
+            &mut result,
+            TemplateMatchModes::TM_CCOEFF_NORMED as i32,
+            &self.mask,
+        )?;
+        let mut peak = 0.0;
+        let mut loc = Point::default();
+        min_max_loc(
+            &result,
+            None,
+            Some(&mut peak),
+            None,
+            Some(&mut loc),
+            &no_array(),
+        )?;
+        // マスクがあると相関が NaN や inf になるところがある
+        if !peak.is_finite() || peak < CURSOR_MIN_PEAK {
+            return Ok(None);
+        }
+        let (px, py) = (search.x + loc.x, search.y + loc.y);
+        Ok(Some(CursorPos {
+            px,
+            py,
+            x: self.fit_x.at(px as f64),
+            y: self.fit_y.at(py as f64),
+            peak,
+        }))
+    }
+}
+
+/// 評定画面のフレームごとのポインタの位置．何度目の trial か，フレーム，位置
+pub type CursorSample = (u32, Frame, Option<CursorPos>);
+
+/// trial ごとに `{dir}/trial_001.csv` (`frame,sec,px,py,x,y,peak`) を書く．
+/// x, y の列名と値は scale の設定による．ポインタが見えなかったフレームは位置を空にする
+pub fn report_trajectories(
+    samples: &[CursorSample],
+    timing: &Timing,
+    scale: &ScaleDef,
+    dir: &Path,
+) -> std::io::Result<()> {
+    if !dir.exists() {
+        fs::create_dir(dir)?;
+    }
+    for trial in group_by(samples, |s| s.0) {
+        let mut f = BufWriter::new(fs::File::create(
+            dir.join(format!("trial_{:03}.csv", trial[0].0)),
+        )?);
+        writeln!(
+            &mut f,
+            "frame,sec,px,py,{},{},peak",
+            scale.x_name, scale.y_name
+        )?;
+        for (_, frame, pos) in trial {
+            let sec = timing.sec(frame);
+            match pos {
+                Some(CursorPos { px, py, x, y, peak }) => {
+                    let x = scale.map_x(x);
+                    let y = scale.map_y(y);
+                    writeln!(&mut f, "{frame},{sec},{px},{py},{x},{y},{peak}")?
+                }
+                None => writeln!(&mut f, "{frame},{sec},,,,,")?,
+            }
+        }
+        f.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,269 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::states::State;
+
+/// 画面が切り替わるたびに払う対数尤度の既定値．
+/// だいたい数フレーム分の証拠がそろわないと切り替わらない
+pub const DEFAULT_SWITCH_PENALTY: f64 = 5.0;
+
+/// スコア / 閾値 がこれより小さくても，これとみなす (ln が発散しないように)
+const MIN_RATIO: f64 = 1e-3;
+
+fn default_switch_penalty() -> f64 {
+    DEFAULT_SWITCH_PENALTY
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// 画面の遷移のモデル．screens の TOML の `[model]` に書く．
+///
+/// ```toml
+/// [model]
+/// switch_penalty = 5.0
+/// [model.next]
+/// fixation = ["stimulus"]
+/// stimulus = ["va"]
+/// va = ["fixation"]
+/// ```
+///
+/// 同じ画面に留まるのはいつでも良い．next に書いた遷移だけを許す．
+/// next に `unknown` が出てこなければ，unknown へはどこからでも行けて，どこへでも戻れる
+pub struct ModelDef {
+    #[serde(default = "default_switch_penalty")]
+    pub switch_penalty: f64,
+    /// 画面の名前 → そこから次に行ける画面の名前．空なら全部の遷移を許す
+    #[serde(default)]
+    pub next: BTreeMap<String, Vec<String>>,
+}
+
+impl Default for ModelDef {
+    fn default() -> Self {
+        ModelDef {
+            switch_penalty: DEFAULT_SWITCH_PENALTY,
+            next: BTreeMap::new(),
+        }
+    }
+}
+
+/// 状態 i は i < n なら screens[i]，n なら unknown
+pub struct TransitionModel {
+    /// allowed[i][j]: i から j に行けるか
+    allowed: Vec<Vec<bool>>,
+    switch_penalty: f64,
+}
+
+impl TransitionModel {
+    /// names は画面の名前 (screens の順)．知らない名前があれば Err にその名前
+    pub fn new(def: &ModelDef, names: &[String]) -> Result<Self, String> {
+        let n = names.len();
+        let index = |name: &str| -> Result<usize, String> {
+            if name == "unknown" {
+                return Ok(n);
+            }
+            names
+                .iter()
+                .position(|s| s == name)
+                .ok_or_else(|| format!("unknown screen {name:?} in [model.next]"))
+        };
+        let mut allowed = vec![vec![def.next.is_empty(); n + 1]; n + 1];
+        let mentions_unknown = def
+            .next
+            .iter()
+            .any(|(from, tos)| from == "unknown" || tos.iter().any(|to| to == "unknown"));
+        for (from, tos) in &def.next {
+            let i = index(from)?;
+            for to in tos {
+                allowed[i][index(to)?] = true;
+            }
+        }
+        for (i, row) in allowed.iter_mut().enumerate() {
+            row[i] = true;
+            if !mentions_unknown {
+                row[n] = true;
+            }
+        }
+        if !mentions_unknown {
+            allowed[n].fill(true);
+        }
+        Ok(TransitionModel {
+            allowed,
+            switch_penalty: def.switch_penalty,
+        })
+    }
+
+    fn log_transition(&self, from: usize, to: usize) -> f64 {
+        if from == to {
+            0.0
+        } else if self.allowed[from][to] {
+            -self.switch_penalty
+        } else {
+            f64::NEG_INFINITY
+        }
+    }
+}
+
+/// 各画面の (スコア / 閾値) から，その画面である対数尤度 (unknown を 0 とする)．
+/// 閾値ちょうどで 0，閾値の半分で ln 2
+fn log_emission(ratio: f64) -> f64 {
+    -ratio.max(MIN_RATIO).ln()
+}
+
+/// ratios[t][i] は t フレーム目の i 番目の画面の (スコア / 閾値)．
+/// 遷移のモデルの下で一番ありそうな状態の列を Viterbi で求める
+pub fn viterbi(ratios: &[Vec<f64>], model: &TransitionModel) -> Vec<State> {
+    let Some(first) = ratios.first() else {
+        return vec![];
+    };
+    let n = first.len();
+    let emission = |t: usize, i: usize| {
+        if i == n {
+            0.0
+        } else {
+            log_emission(ratios[t][i])
+        }
+    };
+    let mut best: Vec<f64> = (0..=n).map(|i| emission(0, i)).collect();
+    // back[t][j]: t フレーム目に j だったときの，一つ前の状態
+    let mut back: Vec<Vec<usize>> = vec![vec![0; n + 1]; ratios.len()];
+    for t in 1..ratios.len() {
+        let mut next = vec![f64::NEG_INFINITY; n + 1];
+        for j in 0..=n {
+            for i in 0..=n {
+                let score = best[i] + model.log_transition(i, j);
+                if score > next[j] {
+                    next[j] = score;
+                    back[t][j] = i;
+                }
+            }
+            next[j] += emission(t, j);
+        }
+        best = next;
+    }
+    let mut state = (0..=n)
+        .max_by(|&a, &b| best[a].total_cmp(&best[b]))
+        .unwrap_or(n);
+    let mut path = vec![0; ratios.len()];
+    for t in (0..ratios.len()).rev() {
+        path[t] = state;
+        state = back[t][state];
+    }
+    path.into_iter()
+        .map(|i| {
+            if i == n {
+                State::Unknown
+            } else {
+                State::Screen(i)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn model(next: &[(&str, &[&str])], switch_penalty: f64) -> ModelDef {
+        ModelDef {
+            switch_penalty,
+            next: next
+                .iter()
+                .map(|(from, tos)| (from.to_string(), names(tos)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn empty_model_allows_everything() {
+        let m = TransitionModel::new(&ModelDef::default(), &names(&["a", "b"])).unwrap();
+        assert!(m.allowed.iter().all(|row| row.iter().all(|&ok| ok)));
+    }
+
+    #[test]
+    fn unknown_is_free_unless_mentioned() {
+        let m = TransitionModel::new(&model(&[("a", &["b"])], 1.0), &names(&["a", "b"])).unwrap();
+        // a -> b だけ．unknown (2) へはどこからでも行けて，どこへでも戻れる
+        assert_eq!(
+            m.allowed,
+            vec![
+                vec![true, true, true],
+                vec![false, true, true],
+                vec![true, true, true],
+            ]
+        );
+    }
+
+    #[test]
+    fn mentioned_unknown_follows_next() {
+        let def = model(&[("a", &["unknown"]), ("unknown", &["b"])], 1.0);
+        let m = TransitionModel::new(&def, &names(&["a", "b"])).unwrap();
+        assert_eq!(
+            m.allowed,
+            vec![
+                vec![true, false, true],
+                vec![false, true, false],
+                vec![false, true, true],
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_screen_name_is_an_error() {
+        let def = model(&[("a", &["c"])], 1.0);
+        let err = TransitionModel::new(&def, &names(&["a", "b"]))
+            .err()
+            .unwrap();
+        assert!(err.contains("\"c\""));
+    }
+
+    #[test]
+    fn disallowed_transitions_are_never_taken() {
+        // a -> b -> c -> a の順にしか進めず，unknown にも行けない
+        let def = model(
+            &[
+                ("a", &["b"]),
+                ("b", &["c"]),
+                ("c", &["a"]),
+                ("unknown", &[]),
+            ],
+            0.5,
+        );
+        let m = TransitionModel::new(&def, &names(&["a", "b", "c"])).unwrap();
+        // a がはっきり見えたあと c がはっきり見える．b は見えない
+        let mut ratios = vec![vec![0.1, 5.0, 5.0]; 5];
+        ratios.extend(vec![vec![5.0, 5.0, 0.1]; 5]);
+        let path = viterbi(&ratios, &m);
+        assert_eq!(path[0], State::Screen(0));
+        assert_eq!(path[9], State::Screen(2));
+        for w in path.windows(2) {
+            assert_ne!((w[0], w[1]), (State::Screen(0), State::Screen(2)));
+            assert!(!w.contains(&State::Unknown));
+        }
+        assert!(path.contains(&State::Screen(1)));
+    }
+
+    #[test]
+    fn one_frame_blip_is_suppressed() {
+        let m = TransitionModel::new(&ModelDef::default(), &names(&["a", "b"])).unwrap();
+        let a = vec![0.1, 10.0];
+        let b = vec![10.0, 0.1];
+        let mut ratios = vec![a.clone(); 5];
+        ratios.push(b);
+        ratios.extend(vec![a; 5]);
+        assert!(viterbi(&ratios, &m).iter().all(|&s| s == State::Screen(0)));
+    }
+
+    #[test]
+    fn sustained_change_is_accepted() {
+        let m = TransitionModel::new(&ModelDef::default(), &names(&["a", "b"])).unwrap();
+        let mut ratios = vec![vec![0.1, 10.0]; 5];
+        ratios.extend(vec![vec![10.0, 0.1]; 5]);
+        let path = viterbi(&ratios, &m);
+        assert_eq!(path[..5], [State::Screen(0); 5]);
+        assert_eq!(path[5..], [State::Screen(1); 5]);
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+
+use crate::base::Frame;
+use crate::calibrate::CalibrateError;
+use crate::layout::LayoutError;
+use crate::load::LoadVideoError;
+use crate::match_bw::FindFramesError;
+use crate::scale::ScaleError;
+use crate::states::ScreensError;
+
+#[derive(Debug)]
+/// 結果の CSV の `line` 行目が読めない
+pub struct ParseError {
+    pub line: usize,
+    pub msg: String,
+}
+
+#[derive(Debug)]
+/// このクレートで起きるエラー全部
+pub enum Error {
+    LoadVideoError(LoadVideoError),
+    FindFramesError(FindFramesError),
+    LayoutError(LayoutError),
+    ScreensError(ScreensError),
+    ScaleError(ScaleError),
+    CalibrateError(CalibrateError),
+    OpenCVError(opencv::error::Error),
+    IoError(std::io::Error),
+    ParseError(ParseError),
+    /// 指定したフレームまで動画が続いていない
+    FrameNotRead(Frame),
+    /// screens の設定がおかしい（知らない画面の名前など）
+    InvalidScreens(String),
+    /// 回答のスケールの設定がおかしい（マスが無い，画面からはみ出すなど）
+    InvalidScale(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::LoadVideoError(LoadVideoError::OpenCVError(e)) => {
+                write!(f, "error on opencv while loading: {e}")
+            }
+            Error::LoadVideoError(LoadVideoError::NoFrameError) => write!(
+                f,
+                "CAP_PROP_FRAME_COUNT didn't return positive number; maybe not a video"
+            ),
+            Error::LoadVideoError(LoadVideoError::FileNotFoundError) => {
+                write!(f, "file not found")
+            }
+            Error::FindFramesError(FindFramesError::TemplateNotFound) => {
+                write!(f, "template image not found or empty")
+            }
+            Error::FindFramesError(FindFramesError::OpenCVError(e)) => {
+                write!(f, "error on opencv while matching: {e}")
+            }
+            Error::LayoutError(LayoutError::IoError(e)) => write!(f, "cannot read layout: {e}"),
+            Error::LayoutError(LayoutError::ParseError(e)) => {
+                write!(f, "cannot parse layout: {e}")
+            }
+            Error::ScreensError(ScreensError::IoError(e)) => {
+                write!(f, "cannot read screens: {e}")
+            }
+            Error::ScreensError(ScreensError::ParseError(e)) => {
+                write!(f, "cannot parse screens: {e}")
+            }
+            Error::ScaleError(ScaleError::IoError(e)) => write!(f, "cannot read scale: {e}"),
+            Error::ScaleError(ScaleError::ParseError(e)) => {
+                write!(f, "cannot parse scale: {e}")
+            }
+            Error::CalibrateError(e) => write!(f, "calibration failed: {e}"),
+            Error::OpenCVError(e) => write!(f, "error on opencv: {e}"),
+            Error::IoError(e) => write!(f, "io error: {e}"),
+            Error::ParseError(ParseError { line, msg }) => {
+                write!(f, "parse error at line {line}: {msg}")
+            }
+            Error::InvalidScreens(msg) => write!(f, "invalid screens: {msg}"),
+            Error::InvalidScale(msg) => write!(f, "invalid scale: {msg}"),
+            Error::FrameNotRead(frame) => write!(f, "video not long enough for frame {frame}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<LoadVideoError> for Error {
+    fn from(err: LoadVideoError) -> Error {
+        Error::LoadVideoError(err)
+    }
+}
+
+impl From<FindFramesError> for Error {
+    fn from(err: FindFramesError) -> Error {
+        Error::FindFramesError(err)
+    }
+}
+
+impl From<LayoutError> for Error {
+    fn from(err: LayoutError) -> Error {
+        Error::LayoutError(err)
+    }
+}
+
+impl From<ScreensError> for Error {
+    fn from(err: ScreensError) -> Error {
+        Error::ScreensError(err)
+    }
+}
+
+impl From<ScaleError> for Error {
+    fn from(err: ScaleError) -> Error {
+        Error::ScaleError(err)
+    }
+}
+
+impl From<CalibrateError> for Error {
+    fn from(err: CalibrateError) -> Error {
+        Error::CalibrateError(err)
+    }
+}
+
+impl From<opencv::error::Error> for Error {
+    fn from(err: opencv::error::Error) -> Error {
+        Error::OpenCVError(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::IoError(err)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Error {
+        Error::ParseError(err)
+    }
+}
@@ -1,32 +1,46 @@
 use opencv::prelude::*;
 use opencv::videoio::VideoCapture;
 
+use crate::error::Result;
+use crate::timing::current_sec;
+
 /// 飛び飛びのフレームも想定して，与えられた列のフレームを返す．
-/// !! 結果はフレーム番号の昇順になる．
+/// !! 結果はフレーム番号の昇順になる．同じフレームが何度与えられても一度だけ返す．
 /// * 途中でフレームが空になっても許容することにするが，そこは飛ばす
-/// * returns: [opencv::error::Result]<[Vec]<[Mat]>>
+/// * returns: [crate::error::Result]<[Vec]<[Mat]>>
 ///   [VideoCaptureTrait::read](opencv::prelude::VideoCaptureTrait::read) が Err を返すときに同様にそれを返す
-pub fn get_nth_frames(
+pub fn get_nth_frames(vc: &mut VideoCapture, ns: &[usize]) -> Result<Vec<(usize, Mat)>> {
+    Ok(get_nth_frames_with_stamps(vc, ns)?.0)
+}
+
+/// [get_nth_frames] と同じフレームと，0 フレーム目から最後に読んだフレームまでの
+/// 各フレームの時刻 (秒)．読み飛ばしたフレームの時刻も記録する（[crate::timing::Timing::Stamps] 用）
+pub fn get_nth_frames_with_stamps(
     vc: &mut VideoCapture,
     ns: &[usize],
-) -> opencv::error::Result<Vec<(usize, Mat)>> {
+) -> Result<(Vec<(usize, Mat)>, Vec<f64>)> {
     let mut frames: Vec<usize> = ns.to_vec();
     frames.sort();
+    // 同じフレームを二度読もうとすると次のフレームを読んでしまう
+    frames.dedup();
     let mut n = 0; // 今何フレーム目読んでるか
     let mut img = Mat::default();
     let mut result = vec![];
+    let mut stamps = vec![];
     for &next_target in &frames {
         // 次のところまで読み飛ばす
         while n < next_target {
             vc.grab()?;
+            stamps.push(current_sec(vc)?);
             n += 1;
         }
         let read_st = vc.read(&mut img)?;
+        stamps.push(current_sec(vc)?);
         n += 1;
         // 空じゃないフレームを読めてたら結果に追加
         if read_st {
             result.push((next_target, img.clone()));
         }
     }
-    Ok(result)
+    Ok((result, stamps))
 }
@@ -0,0 +1,116 @@
+#[derive(Debug, Clone, Copy, Default)]
+/// フレームごとの判定 (Vec<bool>) を区間にする前に均す．
+/// 圧縮のノイズで1フレームだけ一致したり外れたりするのを，区間として拾わないように
+pub struct SpanFilter {
+    /// これより短い（フレーム数の）区間は捨てる
+    pub min_len: usize,
+    /// 区間の間の途切れがこのフレーム数以下ならつなぐ
+    pub max_gap: usize,
+    /// (enter, exit)．与えられれば生のスコアから判定し直す．
+    /// スコアが enter 未満になったら区間に入り，exit 以上になるまでは区間が続く．enter < exit
+    pub hysteresis: Option<(f64, f64)>,
+}
+
+impl SpanFilter {
+    /// isvas と scores は同じ長さ（フレームごと）
+    pub fn apply(&self, isvas: &[bool], scores: &[f64]) -> Vec<bool> {
+        let isvas = match self.hysteresis {
+            Some((enter, exit)) => hysteresis(scores, enter, exit),
+            None => isvas.to_vec(),
+        };
+        drop_short(&bridge_gaps(&isvas, self.max_gap), self.min_len)
+    }
+}
+
+/// 同じ値が続くところの (値, 始まり, 長さ)
+fn runs(bools: &[bool]) -> Vec<(bool, usize, usize)> {
+    let mut result: Vec<(bool, usize, usize)> = vec![];
+    for (i, &b) in bools.iter().enumerate() {
+        match result.last_mut() {
+            Some((val, _, len)) if *val == b => *len += 1,
+            _ => result.push((b, i, 1)),
+        }
+    }
+    result
+}
+
+/// スコアが低いほど一致しているとして，enter と exit の二つの閾値で判定する
+pub fn hysteresis(scores: &[f64], enter: f64, exit: f64) -> Vec<bool> {
+    let mut inside = false;
+    scores
+        .iter()
+        .map(|&score| {
+            inside = if inside { score < exit } else { score < enter };
+            inside
+        })
+        .collect()
+}
+
+/// true に挟まれた max_gap フレーム以下の false を true にする
+pub fn bridge_gaps(bools: &[bool], max_gap: usize) -> Vec<bool> {
+    let mut result = bools.to_vec();
+    let runs = runs(bools);
+    for (i, &(val, start, len)) in runs.iter().enumerate() {
+        let is_inner = i > 0 && i + 1 < runs.len();
+        if !val && is_inner && len <= max_gap {
+            result[start..start + len].fill(true);
+        }
+    }
+    result
+}
+
+/// min_len フレームより短い true を false にする
+pub fn drop_short(bools: &[bool], min_len: usize) -> Vec<bool> {
+    let mut result = bools.to_vec();
+    for (val, start, len) in runs(bools) {
+        if val && len < min_len {
+            result[start..start + len].fill(false);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bools(s: &str) -> Vec<bool> {
+        s.chars().map(|c| c == '1').collect()
+    }
+
+    #[test]
+    fn hysteresis_stays_inside_until_exit() {
+        let scores = [0.9, 0.4, 0.6, 0.7, 0.8, 0.6, 0.4];
+        // 0.5 未満で入り，0.8 以上で出る
+        assert_eq!(hysteresis(&scores, 0.5, 0.8), bools("0111001"));
+    }
+
+    #[test]
+    fn bridge_gaps_fills_only_short_inner_gaps() {
+        assert_eq!(bridge_gaps(&bools("1101100011"), 1), bools("1111100011"));
+        assert_eq!(bridge_gaps(&bools("1101100011"), 3), bools("1111111111"));
+        // 端の false は区間に挟まれていないのでそのまま
+        assert_eq!(bridge_gaps(&bools("0011000"), 5), bools("0011000"));
+    }
+
+    #[test]
+    fn drop_short_removes_short_spans() {
+        assert_eq!(drop_short(&bools("1011011100"), 2), bools("0011011100"));
+        assert_eq!(drop_short(&bools("1011011100"), 3), bools("0000011100"));
+        assert_eq!(drop_short(&bools("0110"), 0), bools("0110"));
+    }
+
+    #[test]
+    fn apply_bridges_before_dropping() {
+        let filter = SpanFilter {
+            min_len: 4,
+            max_gap: 1,
+            hysteresis: None,
+        };
+        // つないでから長さを見るので，途切れのある区間は残る
+        assert_eq!(
+            filter.apply(&bools("01101100001"), &[0.0; 11]),
+            bools("01111100000")
+        );
+    }
+}
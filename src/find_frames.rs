@@ -1,84 +1,281 @@
-#![feature(let_chains)]
-use opencv::core::{_InputOutputArray, no_array, Rect};
+use opencv::core::{bitwise_and, Rect};
 use opencv::imgcodecs::{imread, ImreadModes};
 use opencv::imgproc::{cvt_color_def, match_shapes, ColorConversionCodes, ShapeMatchModes};
 use opencv::prelude::*;
-use opencv::videoio::VideoCapture;
+use opencv::videoio::{VideoCapture, CAP_PROP_POS_FRAMES};
 
-use crate::consts;
+use std::thread;
 
-#[derive(Debug)]
-pub enum FindFramesError {
-    TemplateNotFound,
-    OpenCVError(opencv::error::Error),
-}
+use crate::base::{fit_to, Frame};
+use crate::consts;
+use crate::elog;
+use crate::error;
+use crate::layout::Layout;
+use crate::load::load_video;
+use crate::log;
+use crate::mask::load_mask;
+use crate::match_bw::FindFramesError;
+use crate::matcher::{FrameMatcher, Method};
+use crate::threshold::{otsu, AutoThreshold};
+use crate::timing::current_sec;
 
 pub struct Matcher {
     tmpl: Mat,
     match_method: ShapeMatchModes,
-    mask: _InputOutputArray,
+    /// tmpl のうち比べるところ (255)．外は 0 にしてから形を比べる
+    mask: Mat,
+    /// フレームのどこを切り出して tmpl と比べるか
+    roi: Rect,
 }
 
 impl Matcher {
-    fn new(tmpl: Mat) -> Matcher {
-        Matcher {
-            tmpl,
+    fn new(tmpl: Mat, mask: Mat, roi: Rect) -> opencv::Result<Matcher> {
+        let mut masked = Mat::default();
+        bitwise_and(&tmpl, &tmpl, &mut masked, &mask)?;
+        Ok(Matcher {
+            tmpl: masked,
             match_method: ShapeMatchModes::CONTOURS_MATCH_I2,
-            mask: no_array(),
-        }
+            mask,
+            roi,
+        })
     }
 
-    fn from_file(f: &str) -> opencv::Result<Self> {
-        let template = imread(f, ImreadModes::IMREAD_GRAYSCALE as i32)?;
-        Ok(Matcher::new(template))
+    pub fn from_file(f: &str, layout: &Layout) -> Result<Self, FindFramesError> {
+        let tmpl = imread(f, ImreadModes::IMREAD_GRAYSCALE as i32)?;
+        if tmpl.empty() {
+            return Err(FindFramesError::TemplateNotFound);
+        }
+        Ok(Matcher::new(
+            fit_to(tmpl, &layout.va_roi)?,
+            load_mask(f, &layout.va_roi)?,
+            layout.va_roi.into_rect(),
+        )?)
     }
 
-    fn check_frame(&self, frame: &Mat) -> opencv::Result<f64> {
-        let roi = Mat::roi(
-            frame,
-            Rect {
-                x: consts::VA_ROI_X,
-                y: consts::VA_ROI_Y,
-                width: consts::VA_ROI_W,
-                height: consts::VA_ROI_H,
-            },
-        )?;
+    /// Hu モーメントでの形の違い．小さいほど一致している
+    pub(crate) fn check_frame(&self, frame: &Mat) -> opencv::Result<f64> {
+        let roi = Mat::roi(frame, self.roi)?;
         let mut gs_roi = Mat::default();
         cvt_color_def(
             &roi,
             &mut gs_roi,
             ColorConversionCodes::COLOR_BGR2GRAY as i32,
         )?;
-        match_shapes(&self.tmpl, &gs_roi, self.match_method as i32, 0.0)
+        let mut masked = Mat::default();
+        bitwise_and(&gs_roi, &gs_roi, &mut masked, &self.mask)?;
+        match_shapes(&self.tmpl, &masked, self.match_method as i32, 0.0)
+    }
+}
+
+impl FrameMatcher for Matcher {
+    fn score(&self, frame: &Mat) -> opencv::Result<f64> {
+        self.check_frame(frame)
+    }
+
+    fn default_threshold(&self) -> f64 {
+        consts::MATCH_SHAPES_THRESHOLD
+    }
+}
+
+/// 今の位置から最大 limit フレーム（None なら最後まで）読んで判定する
+fn check_frames(
+    matcher: &dyn FrameMatcher,
+    vc: &mut VideoCapture,
+    threshold: &Option<f64>,
+    limit: Option<usize>,
+) -> opencv::Result<Scan> {
+    let mut scan = Scan::default();
+    let mut frame = Mat::default();
+    while limit.is_none_or(|l| scan.isvas.len() < l)
+        && let Ok(b) = vc.read(&mut frame)
+        && b
+    {
+        let score = matcher.score(&frame)?;
+        scan.scores.push(score);
+        scan.isvas.push(matcher.is_match(score, threshold));
+        scan.stamps.push(current_sec(vc)?);
+        frame = Mat::default();
+    }
+    Ok(scan)
+}
+
+#[derive(Debug, Default)]
+/// 動画を読んだ結果．フレームごとに，評定画面だったかどうか，その元のスコア，時刻 (秒)
+pub struct Scan {
+    pub isvas: Vec<bool>,
+    pub scores: Vec<f64>,
+    pub stamps: Vec<f64>,
+}
+
+impl Scan {
+    fn extend_from(&mut self, other: &Scan, skip: usize) {
+        let skip = skip.min(other.isvas.len());
+        self.isvas.extend_from_slice(&other.isvas[skip..]);
+        self.scores.extend_from_slice(&other.scores[skip..]);
+        self.stamps.extend_from_slice(&other.stamps[skip..]);
+    }
+
+    /// 固定の閾値の代わりに，この動画のスコアの分布から閾値を選んで判定し直す．
+    /// 二つの山にはっきり分かれていなければ，テンプレートか ROI が
+    /// この動画に合っていないことが多いので，警告して元の判定のままにする
+    pub fn apply_auto_threshold(&mut self) -> Option<AutoThreshold> {
+        let Some(auto) = otsu(&self.scores) else {
+            elog!("auto_threshold:warning: all frames have the same score; keeping the fixed threshold");
+            return None;
+        };
+        elog!(
+            "auto_threshold: threshold {}, margin {}, separability {:.3} ({} frames below, {} above)",
+            auto.threshold,
+            auto.margin,
+            auto.separability,
+            auto.below,
+            auto.above
+        );
+        if !auto.is_bimodal() {
+            elog!(
+                "auto_threshold:warning: scores are not clearly bimodal; \
+                 the template or ROI may be wrong for this video. keeping the fixed threshold"
+            );
+            return None;
+        }
+        self.isvas = self.scores.iter().map(|&s| s < auto.threshold).collect();
+        Some(auto)
     }
 
-    /// true if that frame matches
-    fn check_video(&self, vc: &mut VideoCapture, threshold: &Option<f64>) -> Vec<bool> {
-        let thresh = threshold.unwrap_or(consts::MATCH_SHAPES_THRESHOLD);
-        let mut isvas = vec![];
-        let mut frame = Mat::default();
-        while let Ok(b) = vc.read(&mut frame)
-            && b
-        {
-            let score = self.check_frame(&frame).expect("check_frame.unwrap");
-            isvas.push(score < thresh);
+    /// self の最後の n フレームと other の最初の n フレームが同じものに見えるか．
+    /// 時刻が 1ms 以内で揃っていて，判定も同じなら同じフレームとみなす
+    fn agrees_with(&self, other: &Scan, n: usize) -> bool {
+        if self.isvas.len() < n || other.isvas.len() < n {
+            return false;
         }
-        isvas
+        let tail = self.isvas.len() - n;
+        self.isvas[tail..] == other.isvas[..n]
+            && self.stamps[tail..]
+                .iter()
+                .zip(&other.stamps[..n])
+                .all(|(a, b)| (a - b).abs() < 0.001)
     }
+}
+
+/// チャンクの境目で，前のチャンクと重ねて読むフレーム数
+const CHUNK_OVERLAP: usize = 16;
+
+/// チャンクによらない，何をどう判定するか
+struct ChunkJob<'a> {
+    file_name: &'a str,
+    layout: &'a Layout,
+    tmpl_file: &'a str,
+    method: Method,
+    threshold: &'a Option<f64>,
+}
 
-    fn make_video_scores(&self, vc: &mut VideoCapture) -> Vec<f64> {
-        let mut scores = vec![];
-        let mut frame = Mat::default();
-        while let Ok(b) = vc.read(&mut frame)
-            && b
-        {
-            scores.push(self.check_frame(&frame).expect("check_frame.unwrap"));
+/// `from` フレーム目から `len` フレーム（None なら最後まで）を判定する．
+/// 自前の VideoCapture と matcher を作るので，別スレッドから呼べる．
+/// `seek` なら CAP_PROP_POS_FRAMES で飛ぶ（速いがキーフレームの都合でずれうる），
+/// そうでなければ頭から grab で読み飛ばす（遅いが確実）
+fn check_chunk(job: &ChunkJob, from: Frame, len: Option<usize>, seek: bool) -> error::Result<Scan> {
+    let (mut vc, _) = load_video(job.file_name)?;
+    let matcher = job.method.load(job.tmpl_file, job.layout)?;
+    if seek {
+        vc.set(CAP_PROP_POS_FRAMES, from as f64)?;
+        let pos = vc.get(CAP_PROP_POS_FRAMES)?;
+        if pos as usize != from {
+            elog!("check_chunk:warning: asked to seek to {from}, but at {pos}");
+        }
+    } else {
+        for _ in 0..from {
+            vc.grab()?;
+        }
+    }
+    Ok(check_frames(matcher.as_ref(), &mut vc, job.threshold, len)?)
+}
+
+/// [do_find_frames] と同じ結果を，動画を chunks 個に分けて並列に読んで得る．
+/// 2つ目以降のチャンクは CHUNK_OVERLAP フレームだけ手前から読み始め，
+/// 重なったところの判定や時刻が前のチャンクと食い違えば（シークがずれたということなので）
+/// そのチャンクだけ頭から読み直す．前のチャンクまでのフレーム数が合わなければ，
+/// 動画全体を頭から順に読み直す．
+pub fn do_find_frames_chunked(
+    file_name: &str,
+    layout: &Layout,
+    tmpl_file: &str,
+    method: Method,
+    threshold: &Option<f64>,
+    chunks: usize,
+) -> error::Result<Scan> {
+    let job = ChunkJob {
+        file_name,
+        layout,
+        tmpl_file,
+        method,
+        threshold,
+    };
+    let (_, frame_count) = load_video(file_name)?;
+    // 各チャンクが重なりより十分長くなるように
+    let chunks = chunks.clamp(1, (frame_count / (CHUNK_OVERLAP * 2)).max(1));
+    let starts: Vec<Frame> = (0..chunks).map(|k| k * frame_count / chunks).collect();
+    // 各チャンクのログはそのスレッドで溜めて，後でチャンクの順にこのスレッドから出す
+    let results: Vec<(error::Result<Scan>, String)> = thread::scope(|s| {
+        let (starts, job) = (&starts, &job);
+        let handles: Vec<_> = starts
+            .iter()
+            .enumerate()
+            .map(|(k, &start)| {
+                s.spawn(move || {
+                    log::buffered(|| {
+                        if k == 0 {
+                            let len = starts.get(1).copied();
+                            return check_chunk(job, 0, len, false);
+                        }
+                        let from = start - CHUNK_OVERLAP;
+                        // 最後のチャンクは CAP_PROP_FRAME_COUNT を信用せず最後まで読む
+                        let len = starts.get(k + 1).map(|&next| next - from);
+                        check_chunk(job, from, len, true)
+                    })
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let mut scan = Scan::default();
+    for (_, chunk_log) in &results {
+        log::replay(chunk_log);
+    }
+    for (k, (chunk, _)) in results.into_iter().enumerate() {
+        let chunk = chunk?;
+        if k == 0 {
+            scan = chunk;
+            continue;
+        }
+        let start = starts[k];
+        if scan.isvas.len() != start {
+            // 前のチャンクが途中で読めなくなったなど．どこで食い違ったか分からないので全部読み直す
+            elog!(
+                "dffc:warning: expected {start} frames before chunk {k}, got {}; rescanning the whole video",
+                scan.isvas.len()
+            );
+            return check_chunk(&job, 0, None, false);
         }
-        scores
+        let chunk = if scan.agrees_with(&chunk, CHUNK_OVERLAP) {
+            chunk
+        } else {
+            elog!("dffc:warning: chunk at frame {start} disagrees at the boundary; rescanning");
+            let len = starts.get(k + 1).map(|&next| next - start + CHUNK_OVERLAP);
+            check_chunk(&job, start - CHUNK_OVERLAP, len, false)?
+        };
+        scan.extend_from(&chunk, CHUNK_OVERLAP);
     }
+    Ok(scan)
 }
 
-pub fn do_find_frames(vc: &mut VideoCapture, threshold: &Option<f64>) -> Vec<bool> {
-    let matcher = Matcher::from_file(consts::TEMPL_FILE).unwrap_or_else(|_| panic!("dff:matcher"));
-    matcher.check_video(vc, threshold)
+pub fn do_find_frames(
+    vc: &mut VideoCapture,
+    layout: &Layout,
+    tmpl_file: &str,
+    method: Method,
+    threshold: &Option<f64>,
+) -> error::Result<Scan> {
+    let matcher = method.load(tmpl_file, layout)?;
+    Ok(check_frames(matcher.as_ref(), vc, threshold, None)?)
 }
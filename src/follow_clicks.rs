@@ -1,103 +1,79 @@
 use std::fs;
 use std::io::{BufWriter, Write};
+use std::path::Path;
 
-use opencv::core::{no_array, Rect};
-use opencv::imgproc::{cvt_color_def, ColorConversionCodes};
+use opencv::core::{no_array, Rect, CV_64F};
+use opencv::imgproc::{cvt_color_def, integral, ColorConversionCodes};
 use opencv::prelude::*;
 use opencv::videoio::VideoCapture;
 
-use crate::base::{group_by, Frame};
-use crate::consts::{
-    GRID_CENTRE_SIZE, GRID_LEN, GRID_NUM, GRID_PADDING, GRID_TOPLEFT_X, GRID_TOPLEFT_Y, TEMPL_FILE,
-};
-use crate::match_bw::BWMatcher;
+use crate::base::{group_by, Frame, Sq};
+use crate::cursor::{report_trajectories, CursorSample, CursorTracker};
+use crate::elog;
+use crate::error;
+use crate::heatmap::RatingMaps;
+use crate::layout::{GridLayout, Layout};
+use crate::match_bw::FindFramesError;
+use crate::matcher::{FrameMatcher, Method};
+use crate::path_metrics::{dwell, Dwell, PathMetrics};
+use crate::scale::{bounding_box, Cell, ScaleDef};
 use crate::span::Span;
+use crate::template::{Provenance, Template};
+use crate::timing::{current_sec, video_fps, Timing};
 
-//      x:0   1  ....
-//   y: ┌───┬───┐
-//   0  │   │   │
-//      ├───┼───┼
-//   1  │   │   │
-//      ├───┼───┼
-//   2  │   │   │
-//      ├───┴───┴
-
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-/// 見たまま，（画像の中の）長方形領域．
-pub struct Sq {
-    x: i32,
-    y: i32,
-    w: i32,
-    h: i32,
-}
-
-/// 上の数え方で (x,y) のグリッドの中心部の座標を得る
-/// [0, 8]
-impl Sq {
-    pub fn grid_at(x: i32, y: i32) -> Self {
-        Sq {
-            x: GRID_TOPLEFT_X + GRID_LEN * x + GRID_PADDING,
-            y: GRID_TOPLEFT_Y + GRID_LEN * y + GRID_PADDING,
-            w: GRID_CENTRE_SIZE,
-            h: GRID_CENTRE_SIZE,
-        }
-    }
-    pub fn into_rect(self) -> Rect {
-        Rect {
-            x: self.x,
-            y: self.y,
-            width: self.w,
-            height: self.h,
-        }
-    }
-}
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-/// 回答の場所を示す，[-4, 4]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+/// 回答の場所を示す．単位と向きは [ScaleDef] の設定どおり（既定では [-4, 4]）
 pub struct GridLoc {
-    x: i8,
-    y: i8,
-}
-
-impl GridLoc {
-    /// [0, 8] から [-4, 4] へ
-    fn from_coordinate(i: u8, j: u8) -> Self {
-        GridLoc {
-            x: i as i8 - (GRID_NUM / 2) as i8,
-            y: (GRID_NUM / 2) as i8 - j as i8,
-        }
-    }
+    pub x: i16,
+    pub y: i16,
 }
 
 #[derive(Debug)]
 /// ある課題での回答
 /// つまり，一つの課題の中でカチカチ動くので，それをまとめたもの
 pub struct TrialResult {
+    /// 何度目の評定画面か（1 から）．anomalies.csv や cursor の `trial_001.csv` と同じ番号で，
+    /// どのマスも決まらなかった評定画面の分も数えるので飛ぶことがある
+    pub index: u32,
     /// この課題全体の開始フレーム
     pub start_frame: Frame,
     /// この課題全体の終了フレーム
     pub end_frame: Frame,
     /// 途中で選んだ座標を含めた回答一覧
-    /// 最初の (0,0) は含める
+    /// 最初の (0,0) は含める．OK を押した後のフラッシュは含めない
     pub res: Vec<Span<GridLoc>>,
+    /// OK を押して全マスが光った最初のフレームと，その時刻 (秒)．
+    /// 押す前に録画が終わったなら None
+    pub confirm: Option<(Frame, f64)>,
 }
 
 /// 一回通しでやった回答
 pub struct Responses {
     rs: Vec<TrialResult>,
+    /// フレーム番号から秒へ
+    timing: Timing,
 }
 
 impl Responses {
-    fn from_indfrval(selections: &[(u32, Frame, GridLoc)]) -> Self {
+    fn from_indfrval(
+        selections: &[(u32, Frame, GridLoc)],
+        confirmations: &[(u32, Frame)],
+        timing: Timing,
+    ) -> Self {
         if selections.is_empty() {
-            return Responses::empty();
+            return Responses::empty(timing);
         }
         let mut results = vec![];
         let trials = group_by(selections, |p| p.0);
         for trial in trials {
             // この trial のなかでの選択ごとにグループ化
             let selections = group_by(&trial, |p| p.2);
+            let confirm = confirmations
+                .iter()
+                .find(|(index, _)| *index == trial[0].0)
+                .map(|&(_, frame)| (frame, timing.sec(frame)));
             let trial_result = TrialResult {
+                index: trial[0].0,
                 start_frame: trial[0].1,
                 end_frame: trial[trial.len() - 1].1,
                 res: selections
@@ -108,55 +84,86 @@ impl Responses {
                         to: gr[gr.len() - 1].1,
                     })
                     .collect(),
+                confirm,
             };
             results.push(trial_result);
         }
-        Responses { rs: results }
+        Responses {
+            rs: results,
+            timing,
+        }
     }
 
-    fn empty() -> Self {
-        Responses { rs: vec![] }
+    fn empty(timing: Timing) -> Self {
+        Responses { rs: vec![], timing }
     }
 
     /// report the result like
-    /// i,start,end,dur,x,y
-    /// 1,124,399,{dur},0,0
-    /// 1,400,989,{dur},3,4
-    /// 1,990,1230,{dur},4,4
-    /// 2,5000,5120,{dur},0,0
-    pub fn report_csv<W: Write>(&self, mut paper: &mut W) {
-        writeln!(&mut paper, "i,start,end,dur,x,y").unwrap();
-        for (i, trial) in self.rs.iter().enumerate() {
+    /// i,start,end,dur,x,y,start_sec,end_sec,dur_sec
+    /// 1,124,399,{dur},0,0,{start_sec},{end_sec},{dur_sec}
+    /// 1,400,989,{dur},3,4,{start_sec},{end_sec},{dur_sec}
+    /// 1,990,1230,{dur},4,4,{start_sec},{end_sec},{dur_sec}
+    /// 2,5000,5120,{dur},0,0,{start_sec},{end_sec},{dur_sec}
+    /// end はその区間の最後のフレームで，dur は `end - start + 1`，end_sec は end の次のフレームの時刻．
+    /// x, y の列名と値は scale の設定 ([ScaleDef::x_name] など) による
+    pub fn report_csv<W: Write>(&self, mut paper: &mut W, scale: &ScaleDef) -> std::io::Result<()> {
+        writeln!(
+            &mut paper,
+            "i,start,end,dur,{},{},start_sec,end_sec,dur_sec",
+            scale.x_name, scale.y_name
+        )?;
+        for trial in &self.rs {
             for res_span in trial.res.iter() {
-                let index = i + 1;
+                let index = trial.index;
                 let from = res_span.from;
                 let to = res_span.to;
-                let dur = to - from;
-                let x = res_span.val.x;
-                let y = res_span.val.y;
-                writeln!(&mut paper, "{index},{from},{to},{dur},{x},{y}").unwrap();
+                let dur = res_span.dur();
+                let start_sec = self.timing.sec(from);
+                // 最後のフレームが終わるのは次のフレームの時刻
+                let end_sec = self.timing.sec(to + 1);
+                let dur_sec = end_sec - start_sec;
+                let x = scale.x_value(&res_span.val);
+                let y = scale.y_value(&res_span.val);
+                writeln!(
+                    &mut paper,
+                    "{index},{from},{to},{dur},{x},{y},{start_sec},{end_sec},{dur_sec}"
+                )?;
             }
         }
-        paper.flush().unwrap();
+        paper.flush()
     }
     /// report the response time to the first click
-    /// i,start,end,init_dur,total_dur,first_x,first_y,final_x,final_y,clicks
-    /// i: ith trial
+    /// i,start,end,init_dur,total_dur,first_x,first_y,final_x,final_y,clicks,
+    /// start_sec,init_dur_sec,total_dur_sec,
+    /// change_dur,change_dur_sec,confirm,confirm_dur,confirm_sec,confirm_dur_sec
+    /// i: 何度目の trial か ([TrialResult::index])
     /// start: 評定開始
     /// end: 最初のクリックのフレーム
-    /// init_dur: 初動（最初のクリック）までの長さ
-    /// total_dur: このtrial全体でどれだけかかったか
+    /// init_dur: 初動（最初のクリック）までの長さ（end のフレームまで含めて数える）
+    /// total_dur: 最後の選択が終わる（OK を押す直前の）フレームまでの長さ（そのフレームも含める）
+    /// *_sec: 上のものを秒で（フレームの時刻から）
     /// first_*: 最初に選んだ点の座標
     /// final_*: 最終的な点の座標
     /// clicks: 何回クリックしたか
-    pub fn report_csv_rts<W: Write>(&self, mut paper: &mut W) {
+    /// change_dur: 最後に選ぶマスを変えた（最終的な点を選んだ）ところまでの長さ
+    /// confirm: OK を押して全マスが光ったフレーム．押す前に録画が終わったなら空
+    /// confirm_dur: OK を押すまでの長さ
+    /// x, y の列名と値は scale の設定による（`first_valence` など）
+    pub fn report_csv_rts<W: Write>(
+        &self,
+        mut paper: &mut W,
+        scale: &ScaleDef,
+    ) -> std::io::Result<()> {
+        let (xn, yn) = (&scale.x_name, &scale.y_name);
         writeln!(
             &mut paper,
-            "i,start,end,init_dur,total_dur,first_x,first_y,final_x,final_y,clicks"
-        )
-        .unwrap();
-        for (i, trial) in self.rs.iter().enumerate() {
-            let index = i + 1;
+            "i,start,end,init_dur,total_dur,\
+             first_{xn},first_{yn},final_{xn},final_{yn},clicks,\
+             start_sec,init_dur_sec,total_dur_sec,\
+             change_dur,change_dur_sec,confirm,confirm_dur,confirm_sec,confirm_dur_sec"
+        )?;
+        for trial in &self.rs {
+            let index = trial.index;
             let start_here = &trial.res[0];
             let from = start_here.from;
             let to = start_here.to;
@@ -167,103 +174,606 @@ impl Responses {
             } else {
                 &trial.res[1]
             };
-            let first_x = first_choice.val.x;
-            let first_y = first_choice.val.y;
+            let first_x = scale.x_value(&first_choice.val);
+            let first_y = scale.y_value(&first_choice.val);
             let final_choice = &trial.res[trial.res.len() - 1];
-            let total_dur = final_choice.to - start_here.from;
-            let final_x = final_choice.val.x;
-            let final_y = final_choice.val.y;
+            let total_dur = final_choice.to + 1 - start_here.from;
+            let final_x = scale.x_value(&final_choice.val);
+            let final_y = scale.y_value(&final_choice.val);
+            let clicks = trial.res.len() - 1;
+            let start_sec = self.timing.sec(from);
+            let init_dur_sec = self.timing.sec(to + 1) - start_sec;
+            let total_dur_sec = self.timing.sec(final_choice.to + 1) - start_sec;
+            let change_dur = final_choice.from - from;
+            let change_dur_sec = self.timing.sec(final_choice.from) - start_sec;
+            let (confirm, confirm_dur, confirm_sec, confirm_dur_sec) = match trial.confirm {
+                Some((frame, sec)) => (
+                    frame.to_string(),
+                    (frame - from).to_string(),
+                    sec.to_string(),
+                    (sec - start_sec).to_string(),
+                ),
+                None => Default::default(),
+            };
+            writeln!(&mut paper, "{index},{from},{to},{init_dur},{total_dur},{first_x},{first_y},{final_x},{final_y},{clicks},{start_sec},{init_dur_sec},{total_dur_sec},{change_dur},{change_dur_sec},{confirm},{confirm_dur},{confirm_sec},{confirm_dur_sec}")?;
+        }
+        paper.flush()
+    }
+
+    /// trial ごとの選択の動き ([PathMetrics])
+    /// i,clicks,path_manhattan,path_euclidean,first_final_dist,x_reversals,y_reversals,
+    /// mean_click_interval_sec,click_intervals_sec
+    /// 距離はマス単位．*_reversals の x, y は scale の列名になる．
+    /// click_intervals_sec はクリックの間の秒を `;` でつないだもの（2回以上クリックしていなければ空）
+    pub fn report_csv_paths<W: Write>(
+        &self,
+        mut paper: &mut W,
+        scale: &ScaleDef,
+    ) -> std::io::Result<()> {
+        let (xn, yn) = (&scale.x_name, &scale.y_name);
+        writeln!(
+            &mut paper,
+            "i,clicks,path_manhattan,path_euclidean,first_final_dist,{xn}_reversals,{yn}_reversals,\
+             mean_click_interval_sec,click_intervals_sec"
+        )?;
+        for trial in &self.rs {
+            let index = trial.index;
             let clicks = trial.res.len() - 1;
-            writeln!(&mut paper, "{index},{from},{to},{init_dur},{total_dur},{first_x},{first_y},{final_x},{final_y},{clicks}").unwrap();
+            let PathMetrics {
+                manhattan,
+                euclidean,
+                first_final,
+                x_reversals,
+                y_reversals,
+                click_intervals,
+            } = PathMetrics::from_spans(&trial.res, &self.timing);
+            let mean_interval = if click_intervals.is_empty() {
+                String::new()
+            } else {
+                (click_intervals.iter().sum::<f64>() / click_intervals.len() as f64).to_string()
+            };
+            let intervals = click_intervals
+                .iter()
+                .map(|sec| sec.to_string())
+                .collect::<Vec<_>>()
+                .join(";");
+            writeln!(&mut paper, "{index},{clicks},{manhattan},{euclidean},{first_final},{x_reversals},{y_reversals},{mean_interval},{intervals}")?;
         }
-        paper.flush().unwrap();
+        paper.flush()
+    }
+
+    /// trial ごと，マスごとの滞在 ([dwell])．trial の中で初めて選んだ順
+    /// i,x,y,dwell_frames,dwell_sec,visits
+    /// x, y の列名と値は scale の設定による
+    pub fn report_csv_dwell<W: Write>(
+        &self,
+        mut paper: &mut W,
+        scale: &ScaleDef,
+    ) -> std::io::Result<()> {
+        writeln!(
+            &mut paper,
+            "i,{},{},dwell_frames,dwell_sec,visits",
+            scale.x_name, scale.y_name
+        )?;
+        for trial in &self.rs {
+            let index = trial.index;
+            for Dwell {
+                loc,
+                frames,
+                sec,
+                visits,
+            } in dwell(&trial.res, &self.timing)
+            {
+                let x = scale.x_value(&loc);
+                let y = scale.y_value(&loc);
+                writeln!(&mut paper, "{index},{x},{y},{frames},{sec},{visits}")?;
+            }
+        }
+        paper.flush()
     }
 }
 
 pub struct ResGatherer {
-    matcher: BWMatcher,
+    matcher: Box<dyn FrameMatcher>,
+    reader: GridReader,
+    /// あれば評定画面のフレームごとにマウスポインタも探す
+    cursor: Option<CursorTracker>,
 }
 
+/// マスの中心部の平均がこれより明るければ選択されている
+pub const SELECTED_BRIGHTNESS: f64 = 200.0;
+
 /// そのまま読み込んだフレーム (frame) に対して，(x,y) が選択されているか？
-fn is_this_selected(frame: &Mat, x: i32, y: i32) -> bool {
-    let roi = Mat::roi(frame, Sq::grid_at(x, y).into_rect()).expect("is_this_selected::roi");
+/// 1マスごとに切り出して変換するので遅い．普段は [GridReader] を使う
+pub fn is_this_selected(frame: &Mat, grid: &GridLayout, x: i32, y: i32) -> opencv::Result<bool> {
+    let roi = Mat::roi(frame, Sq::grid_at(grid, x, y).into_rect())?;
     let mut grayscale_roi = Mat::default();
     cvt_color_def(
         &roi,
         &mut grayscale_roi,
         ColorConversionCodes::COLOR_BGR2GRAY as i32,
-    )
-    .unwrap();
+    )?;
     // grayscale にしてて特に絞ってないので， [255.0,0.0,0.0,0.0] みたいに帰ってくる
-    let mean = opencv::core::mean(&grayscale_roi, &no_array()).unwrap().0[0];
-    mean > 200.0
+    let mean = opencv::core::mean(&grayscale_roi, &no_array())?.0[0];
+    Ok(mean > SELECTED_BRIGHTNESS)
+}
+
+/// グリッド全体を一度だけ切り出して grayscale にし，
+/// その積分画像から全マスの中心部の平均を一度に出す
+pub struct GridReader {
+    cells: Vec<Cell>,
+    /// 全マスの中心部を含む長方形
+    region: Rect,
+}
+
+impl GridReader {
+    /// 既定の (num+1) x (num+1) のグリッド
+    pub fn new(grid: &GridLayout) -> Self {
+        GridReader::from_cells(ScaleDef::default().grid_cells(grid))
+    }
+
+    /// cells は空でないこと ([ScaleDef::cells])
+    pub fn from_cells(cells: Vec<Cell>) -> Self {
+        GridReader {
+            region: bounding_box(&cells).into_rect(),
+            cells,
+        }
+    }
+
+    /// 各マスの中心部の明るさの平均．順番は cells と同じ
+    pub fn cell_means(&self, frame: &Mat) -> opencv::Result<Vec<f64>> {
+        let roi = Mat::roi(frame, self.region)?;
+        let mut grayscale = Mat::default();
+        cvt_color_def(
+            &roi,
+            &mut grayscale,
+            ColorConversionCodes::COLOR_BGR2GRAY as i32,
+        )?;
+        // sums(r, c) は (0,0) から (c-1, r-1) までの和
+        let mut sums = Mat::default();
+        integral(&grayscale, &mut sums, CV_64F)?;
+        let mut means = vec![];
+        for Cell { rect: sq, .. } in &self.cells {
+            let (left, top) = (sq.x - self.region.x, sq.y - self.region.y);
+            let (right, bottom) = (left + sq.w, top + sq.h);
+            let sum = *sums.at_2d::<f64>(bottom, right)?
+                - *sums.at_2d::<f64>(top, right)?
+                - *sums.at_2d::<f64>(bottom, left)?
+                + *sums.at_2d::<f64>(top, left)?;
+            means.push(sum / (sq.w * sq.h) as f64);
+        }
+        Ok(means)
+    }
+
+    /// 選択されている（明るい）マスの一覧
+    pub fn selected(&self, frame: &Mat) -> opencv::Result<Vec<GridLoc>> {
+        let means = self.cell_means(frame)?;
+        Ok(self
+            .cells
+            .iter()
+            .zip(means)
+            .filter(|(_, mean)| *mean > SELECTED_BRIGHTNESS)
+            .map(|(cell, _)| cell.loc)
+            .collect())
+    }
 }
 
 impl ResGatherer {
-    pub fn from_file(f: &str) -> opencv::Result<Self> {
-        let bwm = BWMatcher::from_file(f)?;
-        Ok(ResGatherer { matcher: bwm })
+    /// cells は回答のマスの一覧 ([ScaleDef::cells])．
+    /// cursor が与えられれば，その参照画像でマウスポインタを追う
+    pub fn from_file(
+        f: &str,
+        layout: &Layout,
+        cells: Vec<Cell>,
+        method: Method,
+        cursor: Option<CursorTracker>,
+    ) -> Result<Self, FindFramesError> {
+        Ok(ResGatherer {
+            matcher: method.load(f, layout)?,
+            reader: GridReader::from_cells(cells),
+            cursor,
+        })
+    }
+
+    /// 評定画面のフレームの，グリッドの状態
+    fn classify(&self, frame: &Mat) -> opencv::Result<GridState> {
+        let means = self.reader.cell_means(frame)?;
+        let lit: Vec<GridLoc> = self
+            .reader
+            .cells
+            .iter()
+            .zip(&means)
+            .filter(|(_, mean)| **mean > SELECTED_BRIGHTNESS)
+            .map(|(cell, _)| cell.loc)
+            .collect();
+        Ok(if lit.len() == 1 {
+            GridState::Single(lit[0])
+        } else if lit.len() == means.len() {
+            GridState::AllLit
+        } else {
+            GridState::Ambiguous(means)
+        })
     }
 
-    fn gather_responses(&self, vc: &mut VideoCapture) -> Responses {
+    /// fps が与えられればそれで，なければ各フレームの時刻で秒にする．
+    /// どのマスが選ばれているか決められなかったフレームは anomalies として返す．
+    /// cursor があれば，評定画面のフレームごとのポインタの位置も返す
+    fn gather_responses(
+        &self,
+        vc: &mut VideoCapture,
+        fps: Option<f64>,
+    ) -> error::Result<(Responses, Vec<Anomaly>, Vec<CursorSample>)> {
+        let fallback_fps = video_fps(vc);
+        let mut stamps = vec![];
         let mut frame = Mat::default();
         let mut frame_number = 0;
         let mut index: u32 = 0;
-        let mut is_start_of_trial = true;
+        // 今の trial の，評定画面のフレームとそのグリッドの状態
+        let mut trial: Vec<(Frame, GridState)> = vec![];
         // 何度目のtrial か，フレーム，そこで選択されたマス
         let mut selections: Vec<(u32, Frame, GridLoc)> = vec![];
+        // 何度目のtrial か，OK を押したフレーム
+        let mut confirmations: Vec<(u32, Frame)> = vec![];
+        let mut anomalies = vec![];
+        let mut cursor_samples = vec![];
         while let Ok(b) = vc.read(&mut frame)
             && b
         {
+            stamps.push(current_sec(vc)?);
             // 評定画面についてはチェックする
-            if self.matcher.does_frame_match(&frame, &None) {
-                if is_start_of_trial {
-                    // ここが trial のはじめなのでそれを記録しておく
-                    index += 1;
-                    is_start_of_trial = false;
+            if self.matcher.does_frame_match(&frame, &None)? {
+                trial.push((frame_number, self.classify(&frame)?));
+                if let Some(tracker) = &self.cursor {
+                    // index はまだこの trial の前のもの
+                    cursor_samples.push((index + 1, frame_number, tracker.locate(&frame)?));
                 }
-                // TODO: here it can be made 100x faster
-                let mut selected: Vec<GridLoc> = vec![];
-                for x in 0..=GRID_NUM {
-                    for y in 0..=GRID_NUM {
-                        // x,y が選択されてるか
-                        // 選択したフレームだけ全部真っ白になる
-                        if is_this_selected(&frame, x as i32, y as i32) {
-                            selected.push(GridLoc::from_coordinate(x, y));
-                        }
-                    }
-                }
-                if selected.len() == 1 {
-                    // 1マスだけ選択されていて平和
-                    selections.push((index, frame_number, selected[0]));
-                } else {
-                    // 全体が光る，OK 押下直後のはず
-                    assert_eq!(selected.len(), ((GRID_NUM + 1) * (GRID_NUM + 1)).into());
-                    // そうっぽいので，前回選ばれたマスをそのまま使う．
-                    let last_selection = selections[selections.len() - 1];
-                    assert_eq!(last_selection.1 + 1, frame_number); // ちゃんと直前があるよね？
-                    selections.push((index, frame_number, last_selection.2));
-                }
-            } else {
-                // ここは評定画面外．次に評定画面が出てきたら，それはその開始フレームだ
-                is_start_of_trial = true;
+            } else if !trial.is_empty() {
+                // ここは評定画面外．trial が終わったのでまとめる
+                index += 1;
+                resolve_trial(
+                    index,
+                    &trial,
+                    &mut selections,
+                    &mut confirmations,
+                    &mut anomalies,
+                );
+                trial.clear();
             }
             // counting the frame manually
             frame_number += 1;
         }
-        Responses::from_indfrval(&selections)
+        // 評定画面のまま動画が終わった
+        if !trial.is_empty() {
+            index += 1;
+            resolve_trial(
+                index,
+                &trial,
+                &mut selections,
+                &mut confirmations,
+                &mut anomalies,
+            );
+        }
+        let timing = Timing::new(stamps, fps, fallback_fps);
+        let responses = Responses::from_indfrval(&selections, &confirmations, timing);
+        Ok((responses, anomalies, cursor_samples))
     }
 }
 
-pub fn do_follow_clicks(vc: &mut VideoCapture, file_name: &str) {
-    let gatherer = ResGatherer::from_file(TEMPL_FILE).unwrap_or_else(|_| panic!("dff:matcher"));
-    let res = gatherer.gather_responses(vc);
+#[derive(Debug, Clone, PartialEq)]
+/// 評定画面のフレームで，グリッドがどう光っているか
+pub enum GridState {
+    /// 1マスだけ選択されていて平和
+    Single(GridLoc),
+    /// 全体が光る，OK 押下直後のはず
+    AllLit,
+    /// どちらでもない（フェード中など）．各マスの明るさ ([GridReader::cell_means]) を持っておく
+    Ambiguous(Vec<f64>),
+}
+
+#[derive(Debug)]
+/// どのマスが選ばれているか決められなかったフレーム
+pub struct Anomaly {
+    pub trial: u32,
+    pub frame: Frame,
+    pub state: GridState,
+}
+
+/// resolve_trial の中での，フレームごとの結果
+enum Resolved {
+    Cell(GridLoc),
+    /// OK を押した後のフラッシュ
+    Flash,
+    Unresolved,
+}
+
+/// 一つの trial のフレームたちのマスを決めて selections に足す．
+/// 選択の後の最初の AllLit は OK を押したところなので confirmations に足し，
+/// その後のフレーム（フラッシュが消えていくところ，消えた後の1マス）は使わない．
+/// それより前の Ambiguous は直前のフレームのマスを引き継ぐ．
+/// trial の頭の Ambiguous は，最初に決まったマスを使う
+/// （録画が評定の途中から始まったときなど）．
+/// 頭の AllLit は OK を押した後なので，どのマスかわからない．
+/// それは anomalies に足し，その後のフレームは選択後の AllLit と同じく使わない．
+/// 決まらなかったものは anomalies に足す
+fn resolve_trial(
+    index: u32,
+    trial: &[(Frame, GridState)],
+    selections: &mut Vec<(u32, Frame, GridLoc)>,
+    confirmations: &mut Vec<(u32, Frame)>,
+    anomalies: &mut Vec<Anomaly>,
+) {
+    let mut resolved = vec![];
+    let mut last = None;
+    let mut confirmed = false;
+    for (frame, state) in trial {
+        resolved.push(match (state, last) {
+            // OK を押した後は，フラッシュが消えて1マスに戻っても選択ではない
+            (_, _) if confirmed => Resolved::Flash,
+            (GridState::Single(loc), _) => {
+                last = Some(*loc);
+                Resolved::Cell(*loc)
+            }
+            (GridState::AllLit, Some(_)) => {
+                confirmations.push((index, *frame));
+                confirmed = true;
+                Resolved::Flash
+            }
+            (GridState::Ambiguous(_), Some(loc)) => Resolved::Cell(loc),
+            // 録画が OK を押した後から始まった．confirmations には足さない
+            (GridState::AllLit, None) => {
+                confirmed = true;
+                Resolved::Unresolved
+            }
+            (GridState::Ambiguous(_), None) => Resolved::Unresolved,
+        });
+    }
+    // フラッシュの後の1マスは使わない
+    if let Some(first) = resolved.iter().find_map(|res| match res {
+        Resolved::Cell(loc) => Some(*loc),
+        _ => None,
+    }) {
+        for (res, (_, state)) in resolved.iter_mut().zip(trial) {
+            if !matches!(res, Resolved::Unresolved) {
+                break;
+            }
+            if let GridState::Ambiguous(_) = state {
+                *res = Resolved::Cell(first);
+            }
+        }
+    }
+    for (res, (frame, state)) in resolved.into_iter().zip(trial) {
+        match res {
+            Resolved::Cell(loc) => selections.push((index, *frame, loc)),
+            Resolved::Flash => (),
+            Resolved::Unresolved => anomalies.push(Anomaly {
+                trial: index,
+                frame: *frame,
+                state: state.clone(),
+            }),
+        }
+    }
+}
+
+/// trial,frame,sec,state,brightness．brightness は各マスの明るさを `;` でつないだもの
+/// (マスの順番は [GridReader::cell_means] と同じ)
+pub fn report_anomalies<W: Write>(
+    anomalies: &[Anomaly],
+    timing: &Timing,
+    mut paper: &mut W,
+) -> std::io::Result<()> {
+    writeln!(&mut paper, "trial,frame,sec,state,brightness")?;
+    for Anomaly {
+        trial,
+        frame,
+        state,
+    } in anomalies
+    {
+        let sec = timing.sec(*frame);
+        let (state, brightness) = match state {
+            GridState::Single(_) => ("single", String::new()),
+            GridState::AllLit => ("all_lit", String::new()),
+            GridState::Ambiguous(means) => (
+                "ambiguous",
+                means
+                    .iter()
+                    .map(|m| format!("{m:.1}"))
+                    .collect::<Vec<_>>()
+                    .join(";"),
+            ),
+        };
+        writeln!(&mut paper, "{trial},{frame},{sec},{state},{brightness}")?;
+    }
+    paper.flush()
+}
+
+/// gather の設定のうち，動画によらないもの
+pub struct GatherOptions {
+    /// 評定画面の参照画像
+    pub template: Template,
+    pub method: Method,
+    /// あればマウスポインタの参照画像
+    pub cursor: Option<Template>,
+    /// 動画ごとの図を書くか
+    pub heatmaps: bool,
+    pub fps: Option<f64>,
+}
+
+/// base は動画の解像度に合わせる前の layout．
+/// 返り値はディレクトリ全体の図 ([RatingMaps::merge]) に使う．
+/// heatmaps なら，この動画の分の図も `{file}.heatmap.final.png` などに書く
+pub fn do_follow_clicks(
+    vc: &mut VideoCapture,
+    file_name: &str,
+    base: &Layout,
+    scale: &ScaleDef,
+    (width, height): (i32, i32),
+    options: &GatherOptions,
+) -> error::Result<RatingMaps> {
+    let GatherOptions {
+        template,
+        method,
+        cursor,
+        heatmaps,
+        fps,
+    } = options;
+    let layout = base.scaled_to(width, height);
+    let cells = scale.cells(base, width, height)?;
+    let locs: Vec<GridLoc> = cells.iter().map(|cell| cell.loc).collect();
+    let tracker = match cursor {
+        Some(c) => Some(CursorTracker::from_file(
+            &c.image_file(),
+            &cells,
+            base,
+            width,
+            height,
+        )?),
+        None => None,
+    };
+    let gatherer =
+        ResGatherer::from_file(&template.image_file(), &layout, cells, *method, tracker)?;
+    let (res, anomalies, cursor_samples) = gatherer.gather_responses(vc, *fps)?;
     let outfile_clicks = format!("{}.clicks.csv", &file_name);
-    let mut f = BufWriter::new(fs::File::create(&outfile_clicks).unwrap());
-    res.report_csv(&mut f);
-    f.flush().unwrap();
+    let mut f = BufWriter::new(fs::File::create(&outfile_clicks)?);
+    res.report_csv(&mut f, scale)?;
     let outfile_rts = format!("{}.reactiontimes.csv", &file_name);
-    let mut f = BufWriter::new(fs::File::create(&outfile_rts).unwrap());
-    res.report_csv_rts(&mut f);
-    f.flush().unwrap();
+    let mut f = BufWriter::new(fs::File::create(&outfile_rts)?);
+    res.report_csv_rts(&mut f, scale)?;
+    let outfile_paths = format!("{}.paths.csv", &file_name);
+    let mut f = BufWriter::new(fs::File::create(&outfile_paths)?);
+    res.report_csv_paths(&mut f, scale)?;
+    let outfile_dwell = format!("{}.dwell.csv", &file_name);
+    let mut f = BufWriter::new(fs::File::create(&outfile_dwell)?);
+    res.report_csv_dwell(&mut f, scale)?;
+    let outfile_anomalies = format!("{}.anomalies.csv", &file_name);
+    if !anomalies.is_empty() {
+        elog!(
+            "gather:warning: could not tell the selected cell in {} frames; see {outfile_anomalies}",
+            anomalies.len()
+        );
+    }
+    let mut f = BufWriter::new(fs::File::create(&outfile_anomalies)?);
+    report_anomalies(&anomalies, &res.timing, &mut f)?;
+    let mut provenance = Provenance::load(file_name);
+    for outname in [
+        &outfile_clicks,
+        &outfile_rts,
+        &outfile_paths,
+        &outfile_dwell,
+        &outfile_anomalies,
+    ] {
+        provenance.record(outname, std::slice::from_ref(template));
+    }
+    if let Some(cursor) = cursor {
+        let dir = format!("{file_name}.cursor");
+        report_trajectories(&cursor_samples, &res.timing, scale, Path::new(&dir))?;
+        provenance.record(&dir, std::slice::from_ref(cursor));
+        elog!("gather: wrote cursor trajectories to {dir}/");
+    }
+    provenance.save(file_name)?;
+    let maps = RatingMaps::from_trials(locs, &res.rs, &res.timing);
+    if *heatmaps {
+        maps.write_images(&format!("{file_name}.heatmap"), scale)?;
+    }
+    Ok(maps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: GridLoc = GridLoc { x: 0, y: 0 };
+    const B: GridLoc = GridLoc { x: 1, y: 2 };
+
+    fn amb() -> GridState {
+        GridState::Ambiguous(vec![100.0; 4])
+    }
+
+    /// states を 10 フレーム目からの trial 3 として解決して，
+    /// (選ばれたフレームとマス, OK のフレーム, 決まらなかったフレーム)
+    fn resolve(states: Vec<GridState>) -> (Vec<(Frame, GridLoc)>, Vec<Frame>, Vec<Frame>) {
+        let trial: Vec<(Frame, GridState)> = (10..).zip(states).collect();
+        let (mut selections, mut confirmations, mut anomalies) = (vec![], vec![], vec![]);
+        resolve_trial(
+            3,
+            &trial,
+            &mut selections,
+            &mut confirmations,
+            &mut anomalies,
+        );
+        assert!(selections.iter().all(|&(i, _, _)| i == 3));
+        assert!(confirmations.iter().all(|&(i, _)| i == 3));
+        assert!(anomalies.iter().all(|a| a.trial == 3));
+        (
+            selections.into_iter().map(|(_, f, loc)| (f, loc)).collect(),
+            confirmations.into_iter().map(|(_, f)| f).collect(),
+            anomalies.into_iter().map(|a| a.frame).collect(),
+        )
+    }
+
+    #[test]
+    fn ambiguous_head_takes_the_first_cell() {
+        let (sel, conf, anom) = resolve(vec![amb(), amb(), GridState::Single(A)]);
+        assert_eq!(sel, vec![(10, A), (11, A), (12, A)]);
+        assert!(conf.is_empty());
+        assert!(anom.is_empty());
+    }
+
+    #[test]
+    fn ambiguous_middle_keeps_the_previous_cell() {
+        let (sel, _, anom) = resolve(vec![
+            GridState::Single(A),
+            amb(),
+            amb(),
+            GridState::Single(B),
+        ]);
+        assert_eq!(sel, vec![(10, A), (11, A), (12, A), (13, B)]);
+        assert!(anom.is_empty());
+    }
+
+    #[test]
+    fn all_lit_after_a_selection_is_the_confirmation() {
+        let (sel, conf, anom) = resolve(vec![
+            GridState::Single(A),
+            GridState::Single(B),
+            GridState::AllLit,
+            GridState::AllLit,
+            amb(),
+            // フラッシュが消えた後の1マスは選択ではない
+            GridState::Single(A),
+        ]);
+        assert_eq!(sel, vec![(10, A), (11, B)]);
+        assert_eq!(conf, vec![12]);
+        assert!(anom.is_empty());
+    }
+
+    #[test]
+    fn all_lit_without_a_selection_is_an_anomaly() {
+        let (sel, conf, anom) = resolve(vec![GridState::AllLit]);
+        assert!(sel.is_empty());
+        assert!(conf.is_empty());
+        assert_eq!(anom, vec![10]);
+    }
+
+    #[test]
+    fn frames_after_a_leading_all_lit_are_flash() {
+        let (sel, conf, anom) = resolve(vec![
+            amb(),
+            GridState::AllLit,
+            GridState::AllLit,
+            amb(),
+            GridState::Single(A),
+            GridState::Single(B),
+        ]);
+        assert!(sel.is_empty());
+        assert!(conf.is_empty());
+        assert_eq!(anom, vec![10, 11]);
+    }
+
+    #[test]
+    fn unresolved_trial_reports_every_frame() {
+        let (sel, conf, anom) = resolve(vec![amb(), amb(), amb()]);
+        assert!(sel.is_empty());
+        assert!(conf.is_empty());
+        assert_eq!(anom, vec![10, 11, 12]);
+    }
 }
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+use opencv::core::{Point, Rect, Scalar, Vec3b, Vector, CV_8UC1, CV_8UC3};
+use opencv::imgcodecs::imwrite;
+use opencv::imgproc::{
+    apply_color_map, put_text, rectangle, ColormapTypes, HersheyFonts, FILLED, LINE_8, LINE_AA,
+};
+use opencv::prelude::*;
+
+use crate::follow_clicks::{GridLoc, TrialResult};
+use crate::path_metrics::dwell;
+use crate::scale::{ScaleDef, XAxis, YAxis};
+use crate::timing::Timing;
+
+/// 1マスの大きさ (px)
+const CELL_PX: i32 = 64;
+/// 周りの余白，題名と色の帯のぶん
+const MARGIN: i32 = 40;
+const BAR_WIDTH: i32 = 20;
+
+#[derive(Debug, Clone, Default)]
+/// マスごとの，最終的に選ばれた回数，最初に選ばれた回数，滞在した秒の合計
+pub struct RatingMaps {
+    /// 描くマス．選ばれなかったマスも 0 として描く
+    pub locs: Vec<GridLoc>,
+    pub final_choice: HashMap<GridLoc, f64>,
+    pub first_choice: HashMap<GridLoc, f64>,
+    pub dwell: HashMap<GridLoc, f64>,
+}
+
+impl RatingMaps {
+    /// 一回通しの trial たちから．最初の選択は reactiontimes の first_* と同じ
+    /// （1回もクリックしていなければ最初のマス）
+    pub fn from_trials(locs: Vec<GridLoc>, trials: &[TrialResult], timing: &Timing) -> Self {
+        let mut maps = RatingMaps {
+            locs,
+            ..Default::default()
+        };
+        for trial in trials {
+            let (Some(first), Some(last)) =
+                (trial.res.get(1).or(trial.res.first()), trial.res.last())
+            else {
+                continue;
+            };
+            *maps.first_choice.entry(first.val).or_default() += 1.0;
+            *maps.final_choice.entry(last.val).or_default() += 1.0;
+            for d in dwell(&trial.res, timing) {
+                *maps.dwell.entry(d.loc).or_default() += d.sec;
+            }
+        }
+        maps
+    }
+
+    /// other の分を足す．ディレクトリ全体の図に使う
+    pub fn merge(&mut self, other: &RatingMaps) {
+        for loc in &other.locs {
+            if !self.locs.contains(loc) {
+                self.locs.push(*loc);
+            }
+        }
+        for (to, from) in [
+            (&mut self.final_choice, &other.final_choice),
+            (&mut self.first_choice, &other.first_choice),
+            (&mut self.dwell, &other.dwell),
+        ] {
+            for (loc, v) in from {
+                *to.entry(*loc).or_default() += v;
+            }
+        }
+    }
+
+    /// `{prefix}.final.png`, `{prefix}.first.png`, `{prefix}.dwell.png` を書く
+    pub fn write_images(&self, prefix: &str, scale: &ScaleDef) -> opencv::Result<()> {
+        for (name, title, values, decimals) in [
+            ("final", "final choice (count)", &self.final_choice, 0),
+            ("first", "first choice (count)", &self.first_choice, 0),
+            ("dwell", "dwell time (sec)", &self.dwell, 1),
+        ] {
+            let img = render(&self.locs, values, scale, title, decimals)?;
+            imwrite(&format!("{prefix}.{name}.png"), &img, &Vector::new())?;
+        }
+        Ok(())
+    }
+}
+
+/// 画面で見えるのと同じ並びにしたときの (列, 行)．左上が (0, 0)
+fn place(loc: &GridLoc, locs: &[GridLoc], scale: &ScaleDef) -> (i32, i32) {
+    let min_x = locs.iter().map(|l| l.x).min().unwrap_or(0) as i32;
+    let max_x = locs.iter().map(|l| l.x).max().unwrap_or(0) as i32;
+    let min_y = locs.iter().map(|l| l.y).min().unwrap_or(0) as i32;
+    let max_y = locs.iter().map(|l| l.y).max().unwrap_or(0) as i32;
+    let col = match scale.x_axis {
+        XAxis::Right => loc.x as i32 - min_x,
+        XAxis::Left => max_x - loc.x as i32,
+    };
+    let row = match scale.y_axis {
+        YAxis::Up => max_y - loc.y as i32,
+        YAxis::Down => loc.y as i32 - min_y,
+    };
+    (col, row)
+}
+
+/// 0 から 255 までの viridis の色
+fn colormap() -> opencv::Result<Vec<Scalar>> {
+    let mut gradient = Mat::new_rows_cols_with_default(256, 1, CV_8UC1, Scalar::all(0.0))?;
+    for (i, v) in gradient.data_bytes_mut()?.iter_mut().enumerate() {
+        *v = i as u8;
+    }
+    let mut coloured = Mat::default();
+    apply_color_map(
+        &gradient,
+        &mut coloured,
+        ColormapTypes::COLORMAP_VIRIDIS as i32,
+    )?;
+    (0..256)
+        .map(|i| {
+            let c = coloured.at_2d::<Vec3b>(i, 0)?;
+            Ok(Scalar::new(c[0] as f64, c[1] as f64, c[2] as f64, 0.0))
+        })
+        .collect()
+}
+
+fn text(img: &mut Mat, s: &str, org: Point, size: f64, colour: Scalar) -> opencv::Result<()> {
+    put_text(
+        img,
+        s,
+        org,
+        HersheyFonts::FONT_HERSHEY_SIMPLEX as i32,
+        size,
+        colour,
+        1,
+        LINE_AA,
+        false,
+    )
+}
+
+/// マスを画面と同じ並びに塗って，値を書き込み，右に色の帯をつけた画像
+fn render(
+    locs: &[GridLoc],
+    values: &HashMap<GridLoc, f64>,
+    scale: &ScaleDef,
+    title: &str,
+    decimals: usize,
+) -> opencv::Result<Mat> {
+    let colours = colormap()?;
+    let places: Vec<(i32, i32)> = locs.iter().map(|l| place(l, locs, scale)).collect();
+    let cols = places.iter().map(|p| p.0).max().unwrap_or(0) + 1;
+    let rows = places.iter().map(|p| p.1).max().unwrap_or(0) + 1;
+    let (grid_w, grid_h) = (cols * CELL_PX, rows * CELL_PX);
+    let mut img = Mat::new_rows_cols_with_default(
+        grid_h + 2 * MARGIN,
+        grid_w + 3 * MARGIN + BAR_WIDTH,
+        CV_8UC3,
+        Scalar::all(255.0),
+    )?;
+    let black = Scalar::all(0.0);
+    let white = Scalar::all(255.0);
+    let max = values.values().copied().fold(0.0, f64::max);
+    let level = |v: f64| {
+        if max > 0.0 {
+            ((v / max) * 255.0).round() as usize
+        } else {
+            0
+        }
+    };
+    text(
+        &mut img,
+        title,
+        Point::new(MARGIN, MARGIN * 2 / 3),
+        0.6,
+        black,
+    )?;
+    for (loc, (col, row)) in locs.iter().zip(&places) {
+        let v = values.get(loc).copied().unwrap_or(0.0);
+        let cell = Rect::new(
+            MARGIN + col * CELL_PX,
+            MARGIN + row * CELL_PX,
+            CELL_PX,
+            CELL_PX,
+        );
+        let l = level(v);
+        rectangle(&mut img, cell, colours[l], FILLED, LINE_8, 0)?;
+        rectangle(&mut img, cell, white, 1, LINE_8, 0)?;
+        // viridis は明るい側が黄色なので，そこだけ黒で書く
+        let ink = if l > 160 { black } else { white };
+        text(
+            &mut img,
+            &format!("{v:.decimals$}"),
+            Point::new(cell.x + 6, cell.y + CELL_PX / 2 + 6),
+            0.5,
+            ink,
+        )?;
+    }
+    // 色の帯．上が max
+    let bar_x = MARGIN * 2 + grid_w;
+    for i in 0..grid_h {
+        let l = 255 - (i * 255 / grid_h.max(1)) as usize;
+        rectangle(
+            &mut img,
+            Rect::new(bar_x, MARGIN + i, BAR_WIDTH, 1),
+            colours[l],
+            FILLED,
+            LINE_8,
+            0,
+        )?;
+    }
+    text(
+        &mut img,
+        &format!("{max:.decimals$}"),
+        Point::new(bar_x, MARGIN - 4),
+        0.4,
+        black,
+    )?;
+    text(
+        &mut img,
+        "0",
+        Point::new(bar_x, MARGIN + grid_h + 14),
+        0.4,
+        black,
+    )?;
+    Ok(img)
+}
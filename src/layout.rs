@@ -0,0 +1,154 @@
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+use crate::base::Sq;
+use crate::consts;
+use crate::elog;
+
+#[derive(Debug)]
+/// 設定ファイルが読めない，あるいは中身が TOML として読めない
+pub enum LayoutError {
+    IoError(std::io::Error),
+    ParseError(toml::de::Error),
+}
+
+impl From<std::io::Error> for LayoutError {
+    fn from(err: std::io::Error) -> LayoutError {
+        LayoutError::IoError(err)
+    }
+}
+
+impl From<toml::de::Error> for LayoutError {
+    fn from(err: toml::de::Error) -> LayoutError {
+        LayoutError::ParseError(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+/// 評定グリッドの幾何．意味は [consts] の GRID_* と同じ
+pub struct GridLayout {
+    /// 一番左上，白線の交点
+    pub topleft_x: i32,
+    pub topleft_y: i32,
+    /// 小さいグリッドの大きさ (px)
+    pub len: i32,
+    /// 何個あるか（0 始まりでの最大の添字）
+    pub num: u8,
+    /// 個々のグリッドを見に行く際，上左端から padding だけずらして
+    /// centre_size の正方形をとる
+    pub padding: i32,
+    pub centre_size: i32,
+}
+
+impl Default for GridLayout {
+    fn default() -> Self {
+        GridLayout {
+            topleft_x: consts::GRID_TOPLEFT_X,
+            topleft_y: consts::GRID_TOPLEFT_Y,
+            len: consts::GRID_LEN,
+            num: consts::GRID_NUM,
+            padding: consts::GRID_PADDING,
+            centre_size: consts::GRID_CENTRE_SIZE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+/// 画面上のどこを見るか，の全部．座標は ref_width x ref_height の画面でのもの．
+/// ファイルで一部だけ書いた場合，残りは組み込みの値 ([consts]) になる．
+/// ```toml
+/// ref_width = 2880
+/// ref_height = 1800
+///
+/// [va_roi]
+/// x = 2550
+/// y = 790
+/// w = 90
+/// h = 50
+///
+/// [grid]
+/// topleft_x = 2643
+/// topleft_y = 617
+/// len = 44
+/// ```
+pub struct Layout {
+    /// 座標の基準になる解像度
+    pub ref_width: i32,
+    pub ref_height: i32,
+    /// 評定画面かどうかを判定するための ROI
+    pub va_roi: Sq,
+    pub grid: GridLayout,
+}
+
+impl Default for Layout {
+    /// [consts] にある値そのまま
+    fn default() -> Self {
+        Layout {
+            ref_width: consts::REF_WIDTH,
+            ref_height: consts::REF_HEIGHT,
+            va_roi: Sq {
+                x: consts::VA_ROI_X,
+                y: consts::VA_ROI_Y,
+                w: consts::VA_ROI_W,
+                h: consts::VA_ROI_H,
+            },
+            grid: GridLayout::default(),
+        }
+    }
+}
+
+impl Layout {
+    pub fn from_file(f: &str) -> Result<Self, LayoutError> {
+        let content = fs::read_to_string(f)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    /// ファイルが与えられればそれを，なければ組み込みの値を使う
+    pub fn from_file_or_default(f: Option<&str>) -> Result<Self, LayoutError> {
+        match f {
+            Some(f) => Layout::from_file(f),
+            None => Ok(Layout::default()),
+        }
+    }
+
+    /// width x height の動画に合わせて座標を拡大縮小したもの
+    pub fn scaled_to(&self, width: i32, height: i32) -> Self {
+        if (width, height) == (self.ref_width, self.ref_height) {
+            return *self;
+        }
+        let sx = width as f64 / self.ref_width as f64;
+        let sy = height as f64 / self.ref_height as f64;
+        if (sx - sy).abs() > 0.01 {
+            elog!(
+                "layout:warning: aspect ratio differs from the reference \
+                 ({}x{} vs {width}x{height}); grid cells are scaled by x",
+                self.ref_width,
+                self.ref_height
+            );
+        }
+        let x = |v: i32| (v as f64 * sx).round() as i32;
+        let y = |v: i32| (v as f64 * sy).round() as i32;
+        let g = &self.grid;
+        Layout {
+            ref_width: width,
+            ref_height: height,
+            va_roi: Sq {
+                x: x(self.va_roi.x),
+                y: y(self.va_roi.y),
+                w: x(self.va_roi.w),
+                h: y(self.va_roi.h),
+            },
+            grid: GridLayout {
+                topleft_x: x(g.topleft_x),
+                topleft_y: y(g.topleft_y),
+                len: x(g.len),
+                num: g.num,
+                padding: x(g.padding),
+                centre_size: x(g.centre_size),
+            },
+        }
+    }
+}
@@ -1,7 +1,11 @@
 use opencv::prelude::*;
-use opencv::videoio::{VideoCapture, CAP_PROP_FRAME_COUNT};
+use opencv::videoio::{
+    VideoCapture, CAP_PROP_FPS, CAP_PROP_FRAME_COUNT, CAP_PROP_FRAME_HEIGHT, CAP_PROP_FRAME_WIDTH,
+};
 use std::path::Path;
 
+use crate::elog;
+
 #[derive(Debug)]
 /// OpenCVに起因するエラー，
 /// get(CAP_PROP_FRAME_COUNT) が0 （多分動画じゃない），
@@ -36,22 +40,33 @@ pub fn load_video(f: &str) -> Result<(VideoCapture, usize), LoadVideoError> {
 pub fn load_report(f: &str) -> Option<VideoCapture> {
     match load_video(f) {
         Ok((vc, frame_count)) => {
-            eprintln!("ready to process video with {frame_count} frames");
+            elog!("ready to process video with {frame_count} frames");
             Some(vc)
         }
         Err(LoadVideoError::OpenCVError(oce)) => {
-            eprintln!("Error on opencv: {:?}", oce);
+            elog!("Error on opencv: {:?}", oce);
             None
         }
         Err(LoadVideoError::NoFrameError) => {
-            eprintln!(
-                "error: CAP_PROP_FRAME_COUNT didn't return positive number; maybe not a video"
-            );
+            elog!("error: CAP_PROP_FRAME_COUNT didn't return positive number; maybe not a video");
             None
         }
         Err(LoadVideoError::FileNotFoundError) => {
-            eprintln!("file not found");
+            elog!("file not found");
             None
         }
     }
 }
+
+/// `sec` 秒目が何フレーム目か．動画の持ってる FPS を使う
+pub fn sec_to_frame(vc: &VideoCapture, sec: f64) -> opencv::Result<usize> {
+    let fps = vc.get(CAP_PROP_FPS)?;
+    Ok((fps * sec).floor() as usize)
+}
+
+/// 動画の解像度 (width, height)
+pub fn frame_size(vc: &VideoCapture) -> opencv::Result<(i32, i32)> {
+    let width = vc.get(CAP_PROP_FRAME_WIDTH)?;
+    let height = vc.get(CAP_PROP_FRAME_HEIGHT)?;
+    Ok((width as i32, height as i32))
+}
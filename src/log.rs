@@ -0,0 +1,42 @@
+use std::cell::RefCell;
+use std::fmt::{self, Write};
+
+thread_local! {
+    /// Some のあいだは stderr に出さずにここに溜める
+    static BUFFER: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// `eprintln!` の代わり．[buffered] の中から呼ばれたときはその場で出さずに溜めておく．
+/// 並列に動画を処理するときに，ファイルごとのログが混ざらないように
+#[macro_export]
+macro_rules! elog {
+    ($($arg:tt)*) => {
+        $crate::log::write_line(format_args!($($arg)*))
+    };
+}
+
+pub fn write_line(args: fmt::Arguments) {
+    BUFFER.with(|buffer| match buffer.borrow_mut().as_mut() {
+        Some(buf) => {
+            // String への書き込みは失敗しない
+            let _ = writeln!(buf, "{args}");
+        }
+        None => eprintln!("{args}"),
+    });
+}
+
+/// f を実行して，その間に [elog] で書かれたものを結果と一緒に返す
+pub fn buffered<T, F: FnOnce() -> T>(f: F) -> (T, String) {
+    let previous = BUFFER.with(|buffer| buffer.replace(Some(String::new())));
+    let result = f();
+    let log = BUFFER.with(|buffer| buffer.replace(previous));
+    (result, log.unwrap_or_default())
+}
+
+/// [buffered] で溜めたものを，このスレッドの [elog] と同じところに書く．
+/// 別のスレッドで溜めたログを呼び出し元のログに混ぜるのに使う
+pub fn replay(log: &str) {
+    for line in log.lines() {
+        write_line(format_args!("{line}"));
+    }
+}
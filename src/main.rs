@@ -1,20 +1,39 @@
-use clap::{Args, Parser, Subcommand};
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand};
 use glob::glob;
+use ikfm2502timeit::base::Sq;
+use ikfm2502timeit::calibrate::do_calibrate;
 use ikfm2502timeit::consts;
+use ikfm2502timeit::elog;
+use ikfm2502timeit::error::{Error, Result};
 use ikfm2502timeit::extract::get_nth_frames;
-use ikfm2502timeit::follow_clicks::do_follow_clicks;
-use ikfm2502timeit::load::load_report;
-use ikfm2502timeit::match_bw;
-use ikfm2502timeit::prepare::prepare;
-use ikfm2502timeit::SimpleSpans;
+use ikfm2502timeit::filter::SpanFilter;
+use ikfm2502timeit::find_frames;
+use ikfm2502timeit::follow_clicks::{do_follow_clicks, GatherOptions};
+use ikfm2502timeit::heatmap::RatingMaps;
+use ikfm2502timeit::layout::Layout;
+use ikfm2502timeit::load::{frame_size, load_video, sec_to_frame};
+use ikfm2502timeit::log;
+use ikfm2502timeit::matcher::Method;
+use ikfm2502timeit::prepare::{prepare, spread_frames, MAX_TEMPLATE_FRAMES};
+use ikfm2502timeit::scale::ScaleDef;
+use ikfm2502timeit::scores::{do_scores, histogram};
+use ikfm2502timeit::span::Spans;
+use ikfm2502timeit::states::{do_states, Screens};
+use ikfm2502timeit::template::{self, Provenance, Template};
+use ikfm2502timeit::timing::{video_fps, Timing};
 use opencv::core::Vector;
 use opencv::imgcodecs::imwrite;
 
 use std::fs;
 use std::io::prelude::*;
-use std::io::BufWriter;
+use std::io::{self, BufWriter};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
 use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
 
 #[derive(Parser, Debug)]
 #[command(version, arg_required_else_help = true)]
@@ -23,12 +42,34 @@ struct Cli {
     #[clap(flatten)]
     file_or_dir: FileOrDir,
 
+    /// ROI やグリッドの位置を書いた設定ファイル (TOML)．無ければ組み込みの値
+    #[arg(short, long)]
+    layout: Option<String>,
+
+    /// 回答のスケール（マスの数，原点，軸の向き，任意のマスの一覧）の設定ファイル (TOML)．
+    /// 無ければ layout の 9x9 のグリッド
+    #[arg(long)]
+    scale: Option<String>,
+
+    /// -d のとき，いくつの動画を同時に処理するか (process, gather, extract-trials)
+    #[arg(short, long, default_value_t = 1)]
+    jobs: usize,
+
+    /// 秒への換算をこの FPS で行う．無ければ各フレームの時刻 (CAP_PROP_POS_MSEC) を使う
+    #[arg(long)]
+    fps: Option<f64>,
+
+    /// 使う（prepare なら作る）参照画像の名前．`data/{NAME}.png` など
+    #[arg(short, long, default_value = consts::DEFAULT_TEMPLATE)]
+    template: String,
+
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Debug, Args)]
-#[group(required = true, multiple = false)]
+// templates 以外では必須．main で確かめる
+#[group(required = false, multiple = false)]
 struct FileOrDir {
     #[arg(short, long)]
     file: Option<String>,
@@ -40,31 +81,461 @@ struct FileOrDir {
 enum Commands {
     /// 参照用の切り抜きを作っておく
     Prepare {
+        #[clap(flatten)]
+        at: TemplateFrames,
+        /// 比べるところを白，無視するところを黒にした画像．無ければ白黒の境目以外を比べる
+        #[arg(long)]
+        mask: Option<String>,
+    },
+    Process {
+        /// 一つの動画を chunks 個に分けて並列に読む
         #[arg(long)]
-        sec: f64,
+        chunks: Option<usize>,
+        /// 評定画面の見つけ方
+        #[arg(long, value_enum, default_value_t = Method::Bw)]
+        method: Method,
+        /// 固定の閾値ではなく，この動画のスコアの分布から閾値を選ぶ．
+        /// ヒステリシス (--enter-threshold, --exit-threshold) とは一緒に使えない
+        #[arg(long, conflicts_with_all = ["enter_threshold", "exit_threshold"])]
+        auto_threshold: bool,
+        #[clap(flatten)]
+        filter: FilterArgs,
     },
-    Process,
 
     ExtractTrials {
         #[arg(long)]
         frames_before: usize,
     },
 
-    Gather,
+    Gather {
+        /// 評定画面の見つけ方
+        #[arg(long, value_enum, default_value_t = Method::Bw)]
+        method: Method,
+        /// マウスポインタの参照画像の名前 (`data/{NAME}.png`)．あれば評定画面でポインタを追って，
+        /// trial ごとの軌跡を `{file}.cursor/trial_001.csv` などに書く
+        #[arg(long)]
+        cursor: Option<String>,
+        /// 最終的な選択，最初の選択の回数と滞在時間をマスごとに塗った図を `{file}.heatmap.final.png` などに書く．
+        /// -d なら全部の動画を合わせたものも `{dir}/heatmap.final.png` などに
+        #[arg(long)]
+        heatmaps: bool,
+    },
+
+    /// 各フレームの生のスコアを `{file}.scores.csv` に書き出し，ヒストグラムを出す
+    Scores {
+        /// ヒストグラムの区切りの数
+        #[arg(long, default_value_t = 40)]
+        bins: usize,
+    },
+
+    /// 複数の画面（注視点，刺激，評定など）を見分けて，画面ごとの区間を `{file}.states.csv` に書く
+    States {
+        /// 画面の一覧 (TOML)．各画面の名前，参照画像，ROI，方法，閾値
+        #[arg(long)]
+        screens: String,
+        /// フレームごとに閾値で判定する代わりに，画面の遷移のモデルで Viterbi デコードする
+        #[arg(long)]
+        decode: bool,
+    },
+
+    /// 参照画像の管理
+    Templates {
+        #[command(subcommand)]
+        command: TemplatesCommand,
+    },
+
+    /// 評定グリッドが写っているフレームから白線を探して，layout を書き出す
+    Calibrate {
+        #[clap(flatten)]
+        at: SecOrFrame,
+        /// 書き出す layout ファイル．確認用の画像は `{out}.png`
+        #[arg(long, default_value = "layout.toml")]
+        out: String,
+        /// この範囲 (x y w h) の中だけを探す
+        #[arg(long, num_args = 4, value_names = ["X", "Y", "W", "H"])]
+        search: Option<Vec<i32>>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TemplatesCommand {
+    /// `data/` にある参照画像と，そのメタデータを一覧する
+    List,
+}
+
+#[derive(Debug, Args)]
+/// 区間にする前のフレームごとの判定の均し方
+struct FilterArgs {
+    /// これより短い（フレーム数の）区間は捨てる
+    #[arg(long, default_value_t = 0)]
+    min_span: usize,
+    /// 区間の間の途切れがこのフレーム数以下ならつなぐ
+    #[arg(long, default_value_t = 0)]
+    max_gap: usize,
+    /// スコアがこれ未満になったら区間に入る（--exit-threshold と一緒に）
+    #[arg(long, requires = "exit_threshold")]
+    enter_threshold: Option<f64>,
+    /// スコアがこれ以上になったら区間から出る
+    #[arg(long, requires = "enter_threshold")]
+    exit_threshold: Option<f64>,
+}
+
+impl FilterArgs {
+    /// clap では書けない，引数どうしの関係を確かめる
+    fn check(&self) -> std::result::Result<(), String> {
+        match (self.enter_threshold, self.exit_threshold) {
+            (Some(enter), Some(exit)) if enter >= exit => Err(format!(
+                "--enter-threshold ({enter}) must be less than --exit-threshold ({exit})"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn to_filter(&self) -> SpanFilter {
+        SpanFilter {
+            min_len: self.min_span,
+            max_gap: self.max_gap,
+            hysteresis: self.enter_threshold.zip(self.exit_threshold),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+#[group(required = true, multiple = false)]
+struct SecOrFrame {
+    #[arg(long)]
+    sec: Option<f64>,
+    #[arg(long)]
+    frame: Option<usize>,
+}
+
+#[derive(Debug, Args)]
+#[group(required = true, multiple = false)]
+/// 参照画像を作るフレーム．複数あればピクセルごとの中央値をとる
+struct TemplateFrames {
+    /// この秒のフレーム（複数可）
+    #[arg(long, num_args = 1..)]
+    sec: Vec<f64>,
+    /// このフレーム（複数可）
+    #[arg(long, num_args = 1..)]
+    frame: Vec<usize>,
+    /// `{file}.bw.result.csv` の i 番目の区間から（最大 31 フレーム）
+    #[arg(long)]
+    span: Option<usize>,
 }
 
 fn to_bw_filename(file_name: &str) -> String {
     format!("{}.bw.result.csv", &file_name)
 }
 
+/// 各動画の処理で共通の設定
+struct Settings {
+    layout: Layout,
+    scale: ScaleDef,
+    fps: Option<f64>,
+    template: Template,
+    /// gather で，成功した動画の分を合わせていく
+    rating_maps: Mutex<RatingMaps>,
+}
+
+/// 一つの動画について command を実行する
+fn run_one(file_name: &str, command: &Commands, settings: &Settings) -> Result<()> {
+    let (mut vc, frame_count) = load_video(file_name)?;
+    elog!("ready to process video with {frame_count} frames");
+    // 動画の解像度に合わせる
+    let (width, height) = frame_size(&vc)?;
+    let layout = settings.layout.scaled_to(width, height);
+    match command {
+        Commands::Prepare { at, mask } => {
+            let frames = match at.span {
+                Some(i) => {
+                    let spans = Spans::from_file(&to_bw_filename(file_name))?;
+                    let Some((from, to)) = spans.span(i) else {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("no span {i} in {}", to_bw_filename(file_name)),
+                        )
+                        .into());
+                    };
+                    // 以前の版は動画の最後まで続いた区間の to を1つ先にしていた
+                    let to = to.min(frame_count.saturating_sub(1));
+                    spread_frames(from, to, MAX_TEMPLATE_FRAMES)
+                }
+                None => {
+                    let mut frames = at.frame.clone();
+                    for &sec in &at.sec {
+                        frames.push(sec_to_frame(&vc, sec)?);
+                    }
+                    frames
+                }
+            };
+            prepare(
+                &mut vc,
+                file_name,
+                &frames,
+                &layout,
+                mask.as_deref(),
+                &settings.template,
+                settings.fps,
+            )?;
+        }
+        Commands::Process {
+            chunks,
+            method,
+            auto_threshold,
+            filter,
+        } => {
+            let tmpl_file = settings.template.image_file();
+            let mut scan = match chunks {
+                Some(chunks) => find_frames::do_find_frames_chunked(
+                    file_name, &layout, &tmpl_file, *method, &None, *chunks,
+                )?,
+                None => find_frames::do_find_frames(&mut vc, &layout, &tmpl_file, *method, &None)?,
+            };
+            let auto = if *auto_threshold {
+                scan.apply_auto_threshold()
+            } else {
+                None
+            };
+            let isvas = filter.to_filter().apply(&scan.isvas, &scan.scores);
+            let spans = Spans::from_bools(&isvas);
+            let timing = Timing::new(scan.stamps, settings.fps, video_fps(&vc));
+            let outname = to_bw_filename(file_name);
+            let mut f = BufWriter::new(fs::File::create(&outname)?);
+            spans.report_numbered(&mut f, &timing, None)?;
+            f.flush()?;
+            let mut provenance = Provenance::load(file_name);
+            provenance
+                .record(&outname, std::slice::from_ref(&settings.template))
+                .auto_threshold = auto.map(|auto| auto.threshold);
+            provenance.save(file_name)?;
+        }
+        Commands::ExtractTrials { frames_before } => {
+            let the_file = Path::new(file_name);
+            let base_name: &str = &the_file.file_stem().unwrap_or_default().to_string_lossy();
+            // .mov を落としてディレクトリの名前とする
+            let out_dir = the_file.with_extension("");
+            if out_dir.is_file() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("a FILE named {out_dir:?} exists!!"),
+                )
+                .into());
+            }
+            if !out_dir.exists() {
+                fs::create_dir(&out_dir)?;
+            }
+            // ここにフレームを書き込むようにするわけですね．
+            let parsed = Spans::from_file(&to_bw_filename(file_name))?;
+            let frames: Vec<usize> = parsed
+                .endframes()
+                .iter()
+                .map(|frame| frame.saturating_sub(*frames_before))
+                .collect();
+            for (frame, img) in get_nth_frames(&mut vc, &frames)? {
+                let outfile = out_dir.join(format!("{base_name}_{frame:05}.jpg"));
+                elog!("writing {outfile:?}");
+                imwrite(&outfile.to_string_lossy(), &img, &Vector::new())?;
+                elog!("done: writing {outfile:?}");
+            }
+        }
+        Commands::Gather {
+            method,
+            cursor,
+            heatmaps,
+        } => {
+            let options = GatherOptions {
+                template: settings.template.clone(),
+                method: *method,
+                cursor: cursor.as_deref().map(Template::new),
+                heatmaps: *heatmaps,
+                fps: settings.fps,
+            };
+            let maps = do_follow_clicks(
+                &mut vc,
+                file_name,
+                &settings.layout,
+                &settings.scale,
+                (width, height),
+                &options,
+            )?;
+            settings.rating_maps.lock().unwrap().merge(&maps);
+        }
+        Commands::Scores { bins } => {
+            let tmpl_file = settings.template.image_file();
+            let scores = do_scores(&mut vc, &layout, &tmpl_file, settings.fps)?;
+            let outname = format!("{file_name}.scores.csv");
+            let mut f = BufWriter::new(fs::File::create(&outname)?);
+            scores.report_csv(&mut f)?;
+            settings.template.write_provenance(file_name, &outname)?;
+            for (j, name) in scores.names.iter().enumerate() {
+                elog!(
+                    "histogram of {name}:\n{}",
+                    histogram(&scores.column(j), *bins)
+                );
+            }
+        }
+        Commands::Calibrate { at, out, search } => {
+            let frame = match (at.frame, at.sec) {
+                (Some(frame), _) => frame,
+                // group(required) なのでどちらかはある
+                (None, sec) => sec_to_frame(&vc, sec.unwrap_or_default())?,
+            };
+            let search = search.as_ref().map(|s| Sq {
+                x: s[0],
+                y: s[1],
+                w: s[2],
+                h: s[3],
+            });
+            do_calibrate(&mut vc, frame, &layout, search, out)?;
+        }
+        Commands::States { screens, decode } => {
+            let screens = Screens::from_file(screens)?;
+            do_states(
+                &mut vc,
+                file_name,
+                &screens,
+                &settings.layout,
+                (width, height),
+                *decode,
+                settings.fps,
+            )?;
+        }
+        // 動画を使わないので main で済ませている
+        Commands::Templates { .. } => unreachable!("templates is handled in main"),
+    }
+    Ok(())
+}
+
+/// jobs 個のスレッドで files を手分けして [run_one] する．
+/// jobs > 1 のときは，ファイルごとのログを溜めておいて終わってからまとめて出す．
+/// 返り値は files と同じ順に (ファイル名, 失敗したならその理由)
+fn run_all(
+    files: &[String],
+    jobs: usize,
+    command: &Commands,
+    settings: &Settings,
+) -> Vec<(String, Option<String>)> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![]);
+    let buffer_logs = jobs > 1;
+    thread::scope(|s| {
+        for _ in 0..jobs.clamp(1, files.len().max(1)) {
+            s.spawn(|| {
+                while let Some(file_name) = files.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    let run = || {
+                        elog!("== {file_name}");
+                        // どれか一つが失敗しても（panic しても）他のファイルは続ける
+                        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                            run_one(file_name, command, settings)
+                        }));
+                        let failure = match res {
+                            Ok(Ok(())) => None,
+                            Ok(Err(e)) => Some(e.to_string()),
+                            Err(_) => Some("panicked".to_string()),
+                        };
+                        if let Some(reason) = &failure {
+                            elog!("error: {file_name}: {reason}");
+                        }
+                        failure
+                    };
+                    let failure = if buffer_logs {
+                        let (failure, log) = log::buffered(run);
+                        eprint!("{log}");
+                        failure
+                    } else {
+                        run()
+                    };
+                    results.lock().unwrap().push((file_name.clone(), failure));
+                }
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(name, _)| files.iter().position(|f| f == name));
+    results
+}
+
+/// templates list: 名前，作った元，日時，ハッシュ．
+/// 画像がメタデータを書いた後に書き換えられていれば，そう出す
+fn list_templates() -> Result<()> {
+    for template in template::list_templates()? {
+        let Some(meta) = template.meta() else {
+            println!("{}\t(no metadata)", template.name);
+            continue;
+        };
+        let hash = template::file_hash(Path::new(&template.image_file()))?;
+        let modified = if hash == meta.hash {
+            ""
+        } else {
+            "\t(MODIFIED)"
+        };
+        println!(
+            "{}\t{} frames {:?}\troi {:?}\tbw {}\t{}\t{}{modified}",
+            template.name,
+            meta.source_video,
+            meta.frames,
+            meta.roi,
+            meta.bw_threshold,
+            meta.created,
+            meta.hash
+        );
+    }
+    Ok(())
+}
+
 fn main() -> ExitCode {
     let cli = Cli::parse();
+    let checked = match &cli.command {
+        Commands::Process { filter, .. } => filter.check(),
+        _ => Ok(()),
+    };
+    if let Err(msg) = checked {
+        Cli::command().error(ErrorKind::ValueValidation, msg).exit();
+    }
+    if let Commands::Templates {
+        command: TemplatesCommand::List,
+    } = cli.command
+    {
+        return match list_templates() {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {e}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+    let layout = match Layout::from_file_or_default(cli.layout.as_deref()) {
+        Ok(layout) => layout,
+        Err(e) => {
+            eprintln!(
+                "error: failed to load layout {:?}: {}",
+                cli.layout,
+                Error::from(e)
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+    let scale = match ScaleDef::from_file_or_default(cli.scale.as_deref()) {
+        Ok(scale) => scale,
+        Err(e) => {
+            eprintln!(
+                "error: failed to load scale {:?}: {}",
+                cli.scale,
+                Error::from(e)
+            );
+            return ExitCode::FAILURE;
+        }
+    };
     // 扱うべき動画ファイルのリスト
     let files: Vec<String>;
     if let Some(f) = &cli.file_or_dir.file {
         files = vec![f.clone()];
     } else {
-        let dir_name: &str = cli.file_or_dir.dir.as_deref().unwrap();
+        let Some(dir_name) = cli.file_or_dir.dir.as_deref() else {
+            eprintln!("error: either --file or --dir is required");
+            return ExitCode::FAILURE;
+        };
         // `.mov` は仮定する．
         files = glob(&format!("{dir_name}/*.mov"))
             .unwrap()
@@ -74,53 +545,49 @@ fn main() -> ExitCode {
             .collect();
     }
     eprintln!("{files:?}");
-    for (mut vc, file_name) in files
-        .iter()
-        .zip(files.iter().cloned())
-        // ここで video 以外ははじけてると思うんだけど
-        .filter_map(|(f, name)| Some((load_report(f)?, name)))
+    // 出力が一つのファイルにまとまるものは並列にしない
+    let jobs = match cli.command {
+        Commands::Process { .. }
+        | Commands::ExtractTrials { .. }
+        | Commands::Gather { .. }
+        | Commands::Scores { .. }
+        | Commands::States { .. } => cli.jobs,
+        Commands::Prepare { .. } | Commands::Calibrate { .. } | Commands::Templates { .. } => 1,
+    };
+    let settings = Settings {
+        layout,
+        scale,
+        fps: cli.fps,
+        template: Template::new(&cli.template),
+        rating_maps: Mutex::new(RatingMaps::default()),
+    };
+    let results = run_all(&files, jobs, &cli.command, &settings);
+    // ディレクトリ全体の図．-f のときは動画ごとのものと同じなので書かない
+    if let (Commands::Gather { heatmaps: true, .. }, Some(dir_name)) =
+        (&cli.command, cli.file_or_dir.dir.as_deref())
     {
-        match &cli.command {
-            Commands::Prepare { sec } => {
-                prepare(&mut vc, *sec);
-            }
-            Commands::Process => {
-                let frames = match_bw::do_find_frames(&mut vc, &None);
-                let spans = SimpleSpans::from_bools(&frames);
-                let outname = to_bw_filename(&file_name);
-                let mut f = BufWriter::new(fs::File::create(&outname).unwrap());
-                spans.report(&mut f, consts::DEFAULT_FPS, None);
-                f.flush().unwrap();
-            }
-            Commands::ExtractTrials { frames_before } => {
-                let the_file = Path::new(&file_name);
-                let base_name: &str = the_file.file_stem().unwrap().to_str().unwrap();
-                // .mov を落としてディレクトリの名前とする
-                let out_dir = Path::new(&file_name[..file_name.len() - 4]);
-                if out_dir.is_file() {
-                    panic!("a FILE named {out_dir:?} exists!!");
-                }
-                if !out_dir.exists() {
-                    fs::create_dir(out_dir).unwrap();
-                }
-                // ここにフレームを書き込むようにするわけですね．
-                let parsed = SimpleSpans::from_file(&to_bw_filename(&file_name)).unwrap();
-                let frames: Vec<usize> = parsed
-                    .endframes()
-                    .iter()
-                    .map(|frame| frame - frames_before)
-                    .collect();
-                for (frame, img) in get_nth_frames(&mut vc, &frames).unwrap() {
-                    let outfile = out_dir.join(format!("{base_name}_{frame:05}.jpg"));
-                    eprintln!("writing {outfile:?}");
-                    imwrite(outfile.to_str().unwrap(), &img, &Vector::new()).unwrap();
-                    eprintln!("done: writing {outfile:?}");
-                }
-            }
-            Commands::Gather => {
-                do_follow_clicks(&mut vc, &file_name);
-            }
+        let prefix = Path::new(dir_name).join("heatmap");
+        let maps = settings.rating_maps.into_inner().unwrap();
+        match maps.write_images(&prefix.to_string_lossy(), &settings.scale) {
+            Ok(()) => eprintln!("wrote {}.{{final,first,dwell}}.png", prefix.display()),
+            Err(e) => eprintln!("error: failed to write heatmaps: {e}"),
         }
     }
-    ExitCode::SUCCESS
+    let failed: Vec<(&String, &String)> = results
+        .iter()
+        .filter_map(|(name, failure)| Some((name, failure.as_ref()?)))
+        .collect();
+    eprintln!(
+        "done: {} of {} files succeeded",
+        results.len() - failed.len(),
+        results.len()
+    );
+    for (name, reason) in &failed {
+        eprintln!("failed: {name}: {reason}");
+    }
+    if failed.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
 }
@@ -0,0 +1,51 @@
+use opencv::core::{Scalar, CV_8UC1};
+use opencv::imgcodecs::{imread, ImreadModes};
+use opencv::imgproc::{dilate_def, erode_def, threshold, ThresholdTypes};
+use opencv::prelude::*;
+
+use crate::base::{fit_to, Sq};
+
+/// 参照画像 tmpl_file に対するマスクのファイル名．`va_roi.png` なら `va_roi.mask.png`
+pub fn mask_file(tmpl_file: &str) -> String {
+    match tmpl_file.strip_suffix(".png") {
+        Some(stem) => format!("{stem}.mask.png"),
+        None => format!("{tmpl_file}.mask.png"),
+    }
+}
+
+/// tmpl_file のマスクを sq の大きさで読み込む．比べるピクセルが 255，無視するピクセルが 0．
+/// マスクのファイルが無ければ全部比べる
+pub fn load_mask(tmpl_file: &str, sq: &Sq) -> opencv::Result<Mat> {
+    let mask = imread(&mask_file(tmpl_file), ImreadModes::IMREAD_GRAYSCALE as i32)?;
+    if mask.empty() {
+        return Mat::new_rows_cols_with_default(sq.h, sq.w, CV_8UC1, Scalar::all(255.0));
+    }
+    // 拡大縮小で中間の値になったところも 0/255 に戻す
+    let mut binary = Mat::default();
+    threshold(
+        &fit_to(mask, sq)?,
+        &mut binary,
+        127.0,
+        255.0,
+        ThresholdTypes::THRESH_BINARY as i32,
+    )?;
+    Ok(binary)
+}
+
+/// 2値化した参照画像 bw から，白黒の境目の周り（アンチエイリアスで
+/// 録画ごとに白になったり黒になったりするところ）を除いたマスクを作る
+pub fn mask_from_bw(bw: &Mat) -> opencv::Result<Mat> {
+    // 3x3 で広げても削っても値が変わらないところ = 境目から離れているところ
+    let mut dilated = Mat::default();
+    dilate_def(bw, &mut dilated, &Mat::default())?;
+    let mut eroded = Mat::default();
+    erode_def(bw, &mut eroded, &Mat::default())?;
+    let mut mask = Mat::default();
+    opencv::core::compare(
+        &dilated,
+        &eroded,
+        &mut mask,
+        opencv::core::CmpTypes::CMP_EQ as i32,
+    )?;
+    Ok(mask)
+}
@@ -1,10 +1,13 @@
+use crate::base::fit_to;
 use crate::consts;
+use crate::layout::Layout;
+use crate::mask::load_mask;
+use crate::matcher::FrameMatcher;
 
-use opencv::core::{sum_elems, CmpTypes, Rect};
+use opencv::core::{bitwise_and, count_non_zero, no_array, CmpTypes, Rect};
 use opencv::imgcodecs::{imread, ImreadModes};
 use opencv::imgproc::{cvt_color_def, threshold, ColorConversionCodes, ThresholdTypes};
 use opencv::prelude::*;
-use opencv::videoio::VideoCapture;
 
 #[derive(Debug)]
 pub enum FindFramesError {
@@ -12,18 +15,43 @@ pub enum FindFramesError {
     OpenCVError(opencv::error::Error),
 }
 
+impl From<opencv::error::Error> for FindFramesError {
+    fn from(err: opencv::error::Error) -> FindFramesError {
+        FindFramesError::OpenCVError(err)
+    }
+}
+
 pub struct BWMatcher {
     tmpl: Mat,
+    /// tmpl のうち比べるところ (255)
+    mask: Mat,
+    /// mask の中のピクセル数
+    mask_size: i32,
+    /// フレームのどこを切り出して tmpl と比べるか
+    roi: Rect,
 }
 
-const BW_THRESHOLD: f64 = 32.0;
+pub(crate) const BW_THRESHOLD: f64 = 32.0;
 impl BWMatcher {
-    fn new(tmpl: Mat) -> Self {
-        BWMatcher { tmpl }
+    fn new(tmpl: Mat, mask: Mat, roi: Rect) -> opencv::Result<Self> {
+        let mask_size = count_non_zero(&mask)?.max(1);
+        Ok(BWMatcher {
+            tmpl,
+            mask,
+            mask_size,
+            roi,
+        })
     }
 
-    pub fn from_file(f: &str) -> opencv::Result<Self> {
-        let grayscale = imread(f, ImreadModes::IMREAD_GRAYSCALE as i32)?;
+    /// 参照画像を読み込む．解像度の違う動画用に，`layout.va_roi` の大きさに合わせて拡大縮小する．
+    /// マスク ([load_mask]) があればそれも読む
+    pub fn from_file(f: &str, layout: &Layout) -> Result<Self, FindFramesError> {
+        // imread はファイルが無くても空の Mat を返すだけなので
+        let tmpl = imread(f, ImreadModes::IMREAD_GRAYSCALE as i32)?;
+        if tmpl.empty() {
+            return Err(FindFramesError::TemplateNotFound);
+        }
+        let grayscale = fit_to(tmpl, &layout.va_roi)?;
         let mut bw = Mat::default();
         threshold(
             &grayscale,
@@ -32,19 +60,13 @@ impl BWMatcher {
             255.0,
             ThresholdTypes::THRESH_BINARY as i32,
         )?;
-        Ok(BWMatcher::new(bw))
+        let mask = load_mask(f, &layout.va_roi)?;
+        Ok(BWMatcher::new(bw, mask, layout.va_roi.into_rect())?)
     }
 
-    fn check_frame_match(&self, frame: &Mat) -> opencv::Result<f64> {
-        let roi = Mat::roi(
-            frame,
-            Rect {
-                x: consts::VA_ROI_X,
-                y: consts::VA_ROI_Y,
-                width: consts::VA_ROI_W,
-                height: consts::VA_ROI_H,
-            },
-        )?;
+    /// 2値化して，マスクの中で違うところの割合 (0 から 1)．小さいほど一致している
+    pub(crate) fn check_frame_match(&self, frame: &Mat) -> opencv::Result<f64> {
+        let roi = Mat::roi(frame, self.roi)?;
         let mut gs_roi = Mat::default();
         cvt_color_def(
             &roi,
@@ -60,34 +82,21 @@ impl BWMatcher {
             255.0,
             ThresholdTypes::THRESH_BINARY as i32,
         )?;
-        // 比べて違うところを数える
+        // 比べて，マスクの中で違うところを数える
         let mut compared = Mat::default();
         opencv::core::compare(&self.tmpl, &bw_roi, &mut compared, CmpTypes::CMP_NE as i32)?;
-        sum_elems(&compared).map(|res| res.0[0])
+        let mut masked = Mat::default();
+        bitwise_and(&compared, &self.mask, &mut masked, &no_array())?;
+        Ok(count_non_zero(&masked)? as f64 / self.mask_size as f64)
     }
+}
 
-    pub fn does_frame_match(&self, frame: &Mat, threshold: &Option<f64>) -> bool {
-        let score = self
-            .check_frame_match(frame)
-            .expect("does_frame_match.unwrap");
-        score < threshold.unwrap_or(consts::MATCH_BW_THRESHOLD)
+impl FrameMatcher for BWMatcher {
+    fn score(&self, frame: &Mat) -> opencv::Result<f64> {
+        self.check_frame_match(frame)
     }
 
-    /// true if that frame matches
-    fn check_video(&self, vc: &mut VideoCapture, threshold: &Option<f64>) -> Vec<bool> {
-        let mut isvas = vec![];
-        let mut frame = Mat::default();
-        while let Ok(b) = vc.read(&mut frame)
-            && b
-        {
-            isvas.push(self.does_frame_match(&frame, threshold));
-            frame = Mat::default();
-        }
-        isvas
+    fn default_threshold(&self) -> f64 {
+        consts::MATCH_BW_THRESHOLD
     }
 }
-pub fn do_find_frames(vc: &mut VideoCapture, threshold: &Option<f64>) -> Vec<bool> {
-    let matcher =
-        BWMatcher::from_file(consts::TEMPL_FILE).unwrap_or_else(|_| panic!("dff:matcher"));
-    matcher.check_video(vc, threshold)
-}
@@ -0,0 +1,114 @@
+use opencv::core::{min_max_loc, no_array, Point, Rect};
+use opencv::imgcodecs::{imread, ImreadModes};
+use opencv::imgproc::{cvt_color_def, match_template, ColorConversionCodes, TemplateMatchModes};
+use opencv::prelude::*;
+
+use crate::base::fit_to;
+use crate::consts;
+use crate::layout::Layout;
+use crate::mask::load_mask;
+use crate::match_bw::FindFramesError;
+use crate::matcher::FrameMatcher;
+
+/// ROI の上下左右を，ROI の幅・高さのこの割合だけ広げたところを探す
+const SEARCH_MARGIN: f64 = 0.25;
+
+/// grayscale のまま正規化相互相関 (TM_CCOEFF_NORMED) で比べる．
+/// 明るさやコントラストが少し違っても効き，
+/// ROI より少し広い範囲を探すので，ウィンドウが少しずれていても見つかる
+pub struct NccMatcher {
+    tmpl: Mat,
+    /// tmpl のうち相関を取るところ (255)
+    mask: Mat,
+    /// 参照画像があるはずのところ
+    roi: Rect,
+    /// 実際に探すところ．roi を広げたもの
+    search: Rect,
+}
+
+#[derive(Debug, Clone, Copy)]
+/// 探した結果
+pub struct NccMatch {
+    /// 相関の一番高いところの値 (-1 から 1)
+    pub peak: f64,
+    /// それが見つかった位置の，roi からのずれ (px)
+    pub offset: Point,
+}
+
+impl NccMatcher {
+    pub fn from_file(f: &str, layout: &Layout) -> Result<Self, FindFramesError> {
+        let tmpl = imread(f, ImreadModes::IMREAD_GRAYSCALE as i32)?;
+        if tmpl.empty() {
+            return Err(FindFramesError::TemplateNotFound);
+        }
+        let roi = layout.va_roi.into_rect();
+        let dx = (roi.width as f64 * SEARCH_MARGIN).round() as i32;
+        let dy = (roi.height as f64 * SEARCH_MARGIN).round() as i32;
+        Ok(NccMatcher {
+            tmpl: fit_to(tmpl, &layout.va_roi)?,
+            mask: load_mask(f, &layout.va_roi)?,
+            roi,
+            search: Rect::new(
+                roi.x - dx,
+                roi.y - dy,
+                roi.width + 2 * dx,
+                roi.height + 2 * dy,
+            ),
+        })
+    }
+
+    /// search をフレームからはみ出さないように削ったもの
+    fn search_in(&self, frame: &Mat) -> Rect {
+        let left = self.search.x.max(0);
+        let top = self.search.y.max(0);
+        let right = (self.search.x + self.search.width).min(frame.cols());
+        let bottom = (self.search.y + self.search.height).min(frame.rows());
+        Rect::new(left, top, right - left, bottom - top)
+    }
+
+    /// 探す範囲の中で一番相関が高いところ
+    pub fn find(&self, frame: &Mat) -> opencv::Result<NccMatch> {
+        let search = self.search_in(frame);
+        let window = Mat::roi(frame, search)?;
+        let mut gs_window = Mat::default();
+        cvt_color_def(
+            &window,
+            &mut gs_window,
+            ColorConversionCodes::COLOR_BGR2GRAY as i32,
+        )?;
+        // result(y, x) は tmpl の左上を window の (x, y) に置いたときの相関
+        let mut result = Mat::default();
+        match_template(
+            &gs_window,
+            &self.tmpl,
+            &mut result,
+            TemplateMatchModes::TM_CCOEFF_NORMED as i32,
+            &self.mask,
+        )?;
+        let mut peak = 0.0;
+        let mut loc = Point::default();
+        min_max_loc(
+            &result,
+            None,
+            Some(&mut peak),
+            None,
+            Some(&mut loc),
+            &no_array(),
+        )?;
+        Ok(NccMatch {
+            peak,
+            offset: Point::new(search.x + loc.x - self.roi.x, search.y + loc.y - self.roi.y),
+        })
+    }
+}
+
+impl FrameMatcher for NccMatcher {
+    /// 1 - 相関．小さいほど一致している
+    fn score(&self, frame: &Mat) -> opencv::Result<f64> {
+        Ok(1.0 - self.find(frame)?.peak)
+    }
+
+    fn default_threshold(&self) -> f64 {
+        consts::MATCH_NCC_THRESHOLD
+    }
+}
@@ -0,0 +1,59 @@
+use clap::ValueEnum;
+use opencv::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::find_frames::Matcher;
+use crate::layout::Layout;
+use crate::match_bw::{BWMatcher, FindFramesError};
+use crate::match_ncc::NccMatcher;
+
+/// フレームが評定画面かどうかを，参照画像との違いのスコアで判定するもの．
+/// スコアはどの方法でも「小さいほど一致している」向きにそろえてあり，
+/// 閾値未満なら評定画面とみなす
+pub trait FrameMatcher {
+    /// frame の ROI と参照画像との違い
+    fn score(&self, frame: &Mat) -> opencv::Result<f64>;
+
+    /// 閾値が与えられなかったときに使う値
+    fn default_threshold(&self) -> f64;
+
+    fn is_match(&self, score: f64, threshold: &Option<f64>) -> bool {
+        score < threshold.unwrap_or(self.default_threshold())
+    }
+
+    fn does_frame_match(&self, frame: &Mat, threshold: &Option<f64>) -> opencv::Result<bool> {
+        Ok(self.is_match(self.score(frame)?, threshold))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// 評定画面の見つけ方
+pub enum Method {
+    /// 2値化して違うピクセルを数える
+    #[default]
+    Bw,
+    /// Hu モーメントで形を比べる (match_shapes)
+    Shapes,
+    /// 正規化相互相関 (match_template)
+    Ncc,
+}
+
+impl Method {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Method::Bw => "bw",
+            Method::Shapes => "shapes",
+            Method::Ncc => "ncc",
+        }
+    }
+
+    /// 参照画像 f を読み込んで，この方法の matcher を作る
+    pub fn load(&self, f: &str, layout: &Layout) -> Result<Box<dyn FrameMatcher>, FindFramesError> {
+        Ok(match self {
+            Method::Bw => Box::new(BWMatcher::from_file(f, layout)?),
+            Method::Shapes => Box::new(Matcher::from_file(f, layout)?),
+            Method::Ncc => Box::new(NccMatcher::from_file(f, layout)?),
+        })
+    }
+}
@@ -0,0 +1,164 @@
+use crate::follow_clicks::GridLoc;
+use crate::span::Span;
+use crate::timing::Timing;
+
+#[derive(Debug, Clone, PartialEq)]
+/// 一つの trial の中での選択の動き．距離はマス単位 ([GridLoc] の座標)
+pub struct PathMetrics {
+    /// 選んだマスを順にたどった長さ（最初の (0,0) から）
+    pub manhattan: i32,
+    pub euclidean: f64,
+    /// 最初に選んだマスと最終的なマスの距離
+    pub first_final: f64,
+    /// 動く向きが逆になった回数（x と y 別々に）
+    pub x_reversals: usize,
+    pub y_reversals: usize,
+    /// クリックとその次のクリックの間 (秒)
+    pub click_intervals: Vec<f64>,
+}
+
+impl PathMetrics {
+    /// res は [crate::follow_clicks::TrialResult::res]．最初の区間はクリックの前なので，
+    /// 2番目からの区間の始まりをクリックとみなす
+    pub fn from_spans(res: &[Span<GridLoc>], timing: &Timing) -> Self {
+        let locs: Vec<GridLoc> = res.iter().map(|span| span.val).collect();
+        let steps: Vec<(i32, i32)> = locs
+            .windows(2)
+            .map(|w| ((w[1].x - w[0].x) as i32, (w[1].y - w[0].y) as i32))
+            .collect();
+        let manhattan = steps.iter().map(|(dx, dy)| dx.abs() + dy.abs()).sum();
+        let euclidean = steps
+            .iter()
+            .map(|&(dx, dy)| ((dx * dx + dy * dy) as f64).sqrt())
+            .sum();
+        // 1回もクリックしていなければ最初のマスのまま
+        let first = locs.get(1).or(locs.first());
+        let first_final = match (first, locs.last()) {
+            (Some(a), Some(b)) => {
+                let (dx, dy) = ((b.x - a.x) as f64, (b.y - a.y) as f64);
+                (dx * dx + dy * dy).sqrt()
+            }
+            _ => 0.0,
+        };
+        let clicks: Vec<f64> = res
+            .iter()
+            .skip(1)
+            .map(|span| timing.sec(span.from))
+            .collect();
+        PathMetrics {
+            manhattan,
+            euclidean,
+            first_final,
+            x_reversals: reversals(steps.iter().map(|s| s.0)),
+            y_reversals: reversals(steps.iter().map(|s| s.1)),
+            click_intervals: clicks.windows(2).map(|w| w[1] - w[0]).collect(),
+        }
+    }
+}
+
+/// 0 でない動きの符号が前の 0 でない動きと逆になった回数
+fn reversals(deltas: impl Iterator<Item = i32>) -> usize {
+    let mut last = 0;
+    let mut count = 0;
+    for d in deltas.filter(|&d| d != 0) {
+        if last != 0 && d.signum() != last {
+            count += 1;
+        }
+        last = d.signum();
+    }
+    count
+}
+
+/// マスごとの滞在．trial の中で初めて選んだ順．
+/// to はその区間の最後のフレームなので，次のフレームの時刻までいたとする
+pub fn dwell(res: &[Span<GridLoc>], timing: &Timing) -> Vec<Dwell> {
+    let mut dwells: Vec<Dwell> = vec![];
+    for span in res {
+        let frames = span.dur();
+        let sec = timing.sec(span.to + 1) - timing.sec(span.from);
+        match dwells.iter_mut().find(|d| d.loc == span.val) {
+            Some(d) => {
+                d.frames += frames;
+                d.sec += sec;
+                d.visits += 1;
+            }
+            None => dwells.push(Dwell {
+                loc: span.val,
+                frames,
+                sec,
+                visits: 1,
+            }),
+        }
+    }
+    dwells
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// あるマスにいた長さの合計と，何度来たか
+pub struct Dwell {
+    pub loc: GridLoc,
+    pub frames: usize,
+    pub sec: f64,
+    pub visits: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(x: i16, y: i16, from: usize, to: usize) -> Span<GridLoc> {
+        Span {
+            val: GridLoc { x, y },
+            from,
+            to,
+        }
+    }
+
+    #[test]
+    fn path_lengths_and_intervals() {
+        // (0,0) から (3,0)，(3,4)，(0,4) とクリック
+        let res = [
+            span(0, 0, 0, 9),
+            span(3, 0, 10, 19),
+            span(3, 4, 20, 39),
+            span(0, 4, 40, 49),
+        ];
+        let m = PathMetrics::from_spans(&res, &Timing::Fps(10.0));
+        assert_eq!(m.manhattan, 10);
+        assert!((m.euclidean - 10.0).abs() < 1e-9);
+        // 最初のクリック (3,0) から最後 (0,4) まで
+        assert!((m.first_final - 5.0).abs() < 1e-9);
+        assert_eq!((m.x_reversals, m.y_reversals), (1, 0));
+        assert_eq!(m.click_intervals, vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn no_clicks() {
+        let m = PathMetrics::from_spans(&[span(0, 0, 0, 9)], &Timing::Fps(10.0));
+        assert_eq!(m.manhattan, 0);
+        assert_eq!(m.first_final, 0.0);
+        assert!(m.click_intervals.is_empty());
+    }
+
+    #[test]
+    fn reversals_ignore_pauses() {
+        assert_eq!(reversals([1, 0, 2, -1, 0, -3, 1].into_iter()), 2);
+        assert_eq!(reversals([0, 0].into_iter()), 0);
+    }
+
+    #[test]
+    fn dwell_counts_the_last_frame() {
+        // (1,0) に 0..=4 と 10..=14，(2,0) に 5..=9
+        let res = [span(1, 0, 0, 4), span(2, 0, 5, 9), span(1, 0, 10, 14)];
+        let dwells = dwell(&res, &Timing::Fps(10.0));
+        assert_eq!(dwells.len(), 2);
+        assert_eq!(dwells[0].loc, GridLoc { x: 1, y: 0 });
+        assert_eq!((dwells[0].frames, dwells[0].visits), (10, 2));
+        assert!((dwells[0].sec - 1.0).abs() < 1e-9);
+        assert_eq!((dwells[1].frames, dwells[1].visits), (5, 1));
+        assert!((dwells[1].sec - 0.5).abs() < 1e-9);
+        // 全部足すと trial の長さになる
+        let total: usize = dwells.iter().map(|d| d.frames).sum();
+        assert_eq!(total, 15);
+    }
+}
@@ -1,34 +1,184 @@
-use opencv::core::{Rect, Vector};
-use opencv::imgcodecs::{imwrite, ImwriteFlags};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use opencv::core::{Scalar, Vector, CV_8UC1, CV_8UC3};
+use opencv::imgcodecs::{imread, imwrite, ImreadModes, ImwriteFlags};
+use opencv::imgproc::{cvt_color_def, threshold, ColorConversionCodes, ThresholdTypes};
 use opencv::prelude::*;
-use opencv::videoio::{VideoCapture, CAP_PROP_FPS, CAP_PROP_POS_FRAMES};
+use opencv::videoio::VideoCapture;
 
+use crate::base::{fit_to, Frame};
 use crate::consts;
+use crate::elog;
+use crate::error::{Error, Result};
+use crate::extract::get_nth_frames_with_stamps;
+use crate::layout::Layout;
+use crate::mask::{mask_file, mask_from_bw};
+use crate::match_bw::BW_THRESHOLD;
+use crate::template::{file_hash, now_iso8601, Template, TemplateMeta};
+use crate::timing::{video_fps, Timing};
+
+/// 区間から参照画像を作るときに使うフレームの数の上限
+pub const MAX_TEMPLATE_FRAMES: usize = 31;
+
+/// 中央値との差 (grayscale) がこれ以下なら，そのフレームでそのピクセルは安定していたとみなす
+const STABLE_TOLERANCE: i16 = 16;
+
+/// from から to (含む) までから，なるべく等間隔に最大 n フレーム選ぶ
+pub fn spread_frames(from: Frame, to: Frame, n: usize) -> Vec<Frame> {
+    let len = to.saturating_sub(from) + 1;
+    if len <= n {
+        return (from..=to).collect();
+    }
+    (0..n)
+        .map(|k| from + k * (len - 1) / (n - 1).max(1))
+        .collect()
+}
+
+/// 安定度の画像のファイル名．`va_roi.png` なら `va_roi.stability.png`
+pub fn stability_file(tmpl_file: &str) -> String {
+    match tmpl_file.strip_suffix(".png") {
+        Some(stem) => format!("{stem}.stability.png"),
+        None => format!("{tmpl_file}.stability.png"),
+    }
+}
+
+/// 各ピクセルの中央値
+fn median(values: &mut [u8]) -> u8 {
+    values.sort_unstable();
+    values[values.len() / 2]
+}
 
-/// 与えられたファイルの `at_second` 秒目から該当領域を切り出して保存
-pub fn prepare(vc: &mut VideoCapture, at_second: f64) {
-    let fps = vc.get(CAP_PROP_FPS).unwrap();
-    let frame = (fps * at_second).floor();
-    vc.set(CAP_PROP_POS_FRAMES, frame).unwrap();
+/// 同じ大きさの画像たちの，ピクセル（とチャンネル）ごとの中央値
+fn median_image(imgs: &[Mat], typ: i32) -> opencv::Result<Mat> {
+    let (rows, cols) = (imgs[0].rows(), imgs[0].cols());
+    let mut result = Mat::new_rows_cols_with_default(rows, cols, typ, Scalar::all(0.0))?;
+    let data: Vec<&[u8]> = imgs
+        .iter()
+        .map(|img| img.data_bytes())
+        .collect::<opencv::Result<_>>()?;
+    let mut values = vec![0; imgs.len()];
+    for (i, out) in result.data_bytes_mut()?.iter_mut().enumerate() {
+        for (value, img) in values.iter_mut().zip(&data) {
+            *value = img[i];
+        }
+        *out = median(&mut values);
+    }
+    Ok(result)
+}
+
+/// 各ピクセルで，中央値から STABLE_TOLERANCE 以内だったフレームの割合 x255．
+/// 白いほど安定している．そのままマスクとして使える
+fn stability_map(grays: &[Mat], median: &Mat) -> opencv::Result<Mat> {
+    let mut result =
+        Mat::new_rows_cols_with_default(median.rows(), median.cols(), CV_8UC1, Scalar::all(0.0))?;
+    let data: Vec<&[u8]> = grays
+        .iter()
+        .map(|img| img.data_bytes())
+        .collect::<opencv::Result<_>>()?;
+    let median = median.data_bytes()?;
+    for (i, out) in result.data_bytes_mut()?.iter_mut().enumerate() {
+        let stable = data
+            .iter()
+            .filter(|img| (img[i] as i16 - median[i] as i16).abs() <= STABLE_TOLERANCE)
+            .count();
+        *out = (255 * stable / data.len()) as u8;
+    }
+    Ok(result)
+}
 
-    let mut the_frame = Mat::default();
-    if !vc.read(&mut the_frame).unwrap() {
-        eprintln!("prepare:error: video not long enough");
+/// 動画 file_name の与えられたフレームたちから該当領域 (`layout.va_roi`) を切り出して，
+/// ピクセルごとの中央値を参照画像 template として保存する．
+/// どう作ったかは `{name}.meta.toml` に書く．
+/// 各ピクセルがフレーム間でどれだけ安定していたかの画像も `{name}.stability.png` に保存する．
+/// マスクも一緒に保存する．`mask` が与えられればその画像を，
+/// なければ複数フレームのときは安定度を，1フレームのときは白黒の境目を除いたものを使う．
+/// meta の時刻は fps があればそれで，なければ読んだフレームの時刻から
+pub fn prepare(
+    vc: &mut VideoCapture,
+    file_name: &str,
+    frames: &[Frame],
+    layout: &Layout,
+    mask: Option<&str>,
+    template: &Template,
+    fps: Option<f64>,
+) -> Result<()> {
+    let mut frames = frames.to_vec();
+    frames.sort();
+    frames.dedup();
+    let (read, stamps) = get_nth_frames_with_stamps(vc, &frames)?;
+    if read.len() < frames.len() {
+        let missing = frames.iter().find(|f| !read.iter().any(|(n, _)| n == *f));
+        return Err(Error::FrameNotRead(missing.copied().unwrap_or_default()));
+    }
+    elog!("prepare: building the template from {} frames", read.len());
+    let mut crops = vec![];
+    let mut grays = vec![];
+    for (_, frame) in &read {
+        // data_bytes のために連続したメモリにコピーしておく
+        let crop = Mat::roi(frame, layout.va_roi.into_rect())?.try_clone()?;
+        let mut gray = Mat::default();
+        cvt_color_def(
+            &crop,
+            &mut gray,
+            ColorConversionCodes::COLOR_BGR2GRAY as i32,
+        )?;
+        crops.push(crop);
+        grays.push(gray);
     }
-    let roi = Mat::roi(
-        &the_frame,
-        Rect {
-            x: consts::VA_ROI_X,
-            y: consts::VA_ROI_Y,
-            width: consts::VA_ROI_W,
-            height: consts::VA_ROI_H,
-        },
-    )
-    .unwrap();
-    imwrite(
-        "./data/va_roi.png",
-        &roi,
-        &Vector::from_slice(&[ImwriteFlags::IMWRITE_PNG_COMPRESSION as i32, 9]),
-    )
-    .unwrap();
+    let tmpl = median_image(&crops, CV_8UC3)?;
+    let gray = median_image(&grays, CV_8UC1)?;
+    let stability = stability_map(&grays, &gray)?;
+
+    fs::create_dir_all(consts::TEMPLATE_DIR)?;
+    let image_file = template.image_file();
+    elog!(
+        "prepare: writing template {:?} to {image_file}",
+        template.name
+    );
+    let flags = Vector::from_slice(&[ImwriteFlags::IMWRITE_PNG_COMPRESSION as i32, 9]);
+    imwrite(&image_file, &tmpl, &flags)?;
+    imwrite(&stability_file(&image_file), &stability, &flags)?;
+
+    let mask = match mask {
+        Some(f) => {
+            let given = imread(f, ImreadModes::IMREAD_GRAYSCALE as i32)?;
+            if given.empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("mask image {f:?} not found or empty"),
+                )
+                .into());
+            }
+            fit_to(given, &layout.va_roi)?
+        }
+        None if grays.len() > 1 => stability,
+        None => {
+            let mut bw = Mat::default();
+            threshold(
+                &gray,
+                &mut bw,
+                BW_THRESHOLD,
+                255.0,
+                ThresholdTypes::THRESH_BINARY as i32,
+            )?;
+            mask_from_bw(&bw)?
+        }
+    };
+    let mask_out = mask_file(&image_file);
+    elog!("prepare: writing mask to {mask_out}");
+    imwrite(&mask_out, &mask, &flags)?;
+
+    let timing = Timing::new(stamps, fps, video_fps(vc));
+    template.write_meta(&TemplateMeta {
+        source_video: file_name.to_string(),
+        frames: frames.clone(),
+        secs: frames.iter().map(|&f| timing.sec(f)).collect(),
+        roi: layout.va_roi,
+        bw_threshold: BW_THRESHOLD,
+        created: now_iso8601(),
+        hash: file_hash(Path::new(&image_file))?,
+    })?;
+    Ok(())
 }
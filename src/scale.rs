@@ -0,0 +1,250 @@
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+use crate::base::Sq;
+use crate::error::{self, Error};
+use crate::follow_clicks::GridLoc;
+use crate::layout::{GridLayout, Layout};
+
+#[derive(Debug)]
+/// スケールの設定ファイルが読めない，あるいは中身が TOML として読めない
+pub enum ScaleError {
+    IoError(std::io::Error),
+    ParseError(toml::de::Error),
+}
+
+impl From<std::io::Error> for ScaleError {
+    fn from(err: std::io::Error) -> ScaleError {
+        ScaleError::IoError(err)
+    }
+}
+
+impl From<toml::de::Error> for ScaleError {
+    fn from(err: toml::de::Error) -> ScaleError {
+        ScaleError::ParseError(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// x 座標が増える向き
+pub enum XAxis {
+    #[default]
+    Right,
+    Left,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// y 座標が増える向き
+pub enum YAxis {
+    #[default]
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// 任意の位置のマス．rect は layout の基準の解像度での，明るさを見に行く長方形
+pub struct CellDef {
+    pub rect: Sq,
+    pub x: i16,
+    pub y: i16,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+/// 回答のスケールの形．何も書かなければ layout の grid の (num+1) x (num+1) で，
+/// 真ん中が (0,0)，右と上が正．
+/// 規則的なグリッドなら cols, rows（マスの位置と大きさは layout の grid），
+/// そうでなければ cells にマスを並べる（cells があれば cols などは使わない）．
+/// ```toml
+/// # 7x7 のグリッド，左上が (0,0) で右と下が正
+/// cols = 7
+/// rows = 7
+/// origin_col = 0
+/// origin_row = 0
+/// y_axis = "down"
+/// ```
+/// ```toml
+/// # 横一列のスライダー．1 から 5
+/// [[cells]]
+/// rect = { x = 2600, y = 800, w = 20, h = 20 }
+/// x = 1
+/// y = 0
+/// # ... 以下同様
+/// ```
+/// 軸の名前と値への換算（値 = 座標 * step + offset）．CSV の列名と中身になる
+/// ```toml
+/// x_name = "valence"
+/// y_name = "arousal"
+/// # -4..4 を 1..9 に
+/// x_offset = 5
+/// y_offset = 5
+/// ```
+pub struct ScaleDef {
+    /// 横のマスの数．無ければ grid.num + 1
+    pub cols: Option<u8>,
+    /// 縦のマスの数．無ければ grid.num + 1
+    pub rows: Option<u8>,
+    /// (0,0) になるマスの列と行（0 始まり）．無ければ真ん中
+    pub origin_col: Option<u8>,
+    pub origin_row: Option<u8>,
+    pub x_axis: XAxis,
+    pub y_axis: YAxis,
+    pub cells: Vec<CellDef>,
+    /// CSV の列名
+    pub x_name: String,
+    pub y_name: String,
+    /// 座標から値へ
+    pub x_step: f64,
+    pub x_offset: f64,
+    pub y_step: f64,
+    pub y_offset: f64,
+}
+
+impl Default for ScaleDef {
+    /// 9x9 で -4..4 のまま，列名は x, y
+    fn default() -> Self {
+        ScaleDef {
+            cols: None,
+            rows: None,
+            origin_col: None,
+            origin_row: None,
+            x_axis: XAxis::default(),
+            y_axis: YAxis::default(),
+            cells: vec![],
+            x_name: "x".to_string(),
+            y_name: "y".to_string(),
+            x_step: 1.0,
+            x_offset: 0.0,
+            y_step: 1.0,
+            y_offset: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// 動画の解像度での，見に行く長方形とその座標
+pub struct Cell {
+    pub rect: Sq,
+    pub loc: GridLoc,
+}
+
+/// cells を全部含む一番小さい長方形．cells が空なら大きさ 0
+pub fn bounding_box(cells: &[Cell]) -> Sq {
+    let left = cells.iter().map(|c| c.rect.x).min().unwrap_or(0);
+    let top = cells.iter().map(|c| c.rect.y).min().unwrap_or(0);
+    let right = cells.iter().map(|c| c.rect.x + c.rect.w).max().unwrap_or(0);
+    let bottom = cells.iter().map(|c| c.rect.y + c.rect.h).max().unwrap_or(0);
+    Sq {
+        x: left,
+        y: top,
+        w: right - left,
+        h: bottom - top,
+    }
+}
+
+impl ScaleDef {
+    pub fn from_file(f: &str) -> Result<Self, ScaleError> {
+        let content = fs::read_to_string(f)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    /// ファイルが与えられればそれを，なければ 9x9 のグリッド
+    pub fn from_file_or_default(f: Option<&str>) -> Result<Self, ScaleError> {
+        match f {
+            Some(f) => ScaleDef::from_file(f),
+            None => Ok(ScaleDef::default()),
+        }
+    }
+
+    /// loc の x 座標を値にしたもの
+    pub fn x_value(&self, loc: &GridLoc) -> f64 {
+        self.map_x(loc.x as f64)
+    }
+
+    /// loc の y 座標を値にしたもの
+    pub fn y_value(&self, loc: &GridLoc) -> f64 {
+        self.map_y(loc.y as f64)
+    }
+
+    /// マスの間も含めた（連続の）x 座標を値に
+    pub fn map_x(&self, x: f64) -> f64 {
+        x * self.x_step + self.x_offset
+    }
+
+    pub fn map_y(&self, y: f64) -> f64 {
+        y * self.y_step + self.y_offset
+    }
+
+    /// 規則的なグリッドのマスの一覧．列ごとに上から
+    pub fn grid_cells(&self, grid: &GridLayout) -> Vec<Cell> {
+        let cols = self.cols.unwrap_or(grid.num + 1);
+        let rows = self.rows.unwrap_or(grid.num + 1);
+        let origin_col = self.origin_col.unwrap_or(cols.saturating_sub(1) / 2) as i16;
+        let origin_row = self.origin_row.unwrap_or(rows.saturating_sub(1) / 2) as i16;
+        let mut cells = vec![];
+        for c in 0..cols {
+            for r in 0..rows {
+                let x = c as i16 - origin_col;
+                let y = r as i16 - origin_row;
+                cells.push(Cell {
+                    rect: Sq::grid_at(grid, c as i32, r as i32),
+                    loc: GridLoc {
+                        x: match self.x_axis {
+                            XAxis::Right => x,
+                            XAxis::Left => -x,
+                        },
+                        // 画面の y は下向き
+                        y: match self.y_axis {
+                            YAxis::Up => -y,
+                            YAxis::Down => y,
+                        },
+                    },
+                });
+            }
+        }
+        cells
+    }
+
+    /// width x height の動画でのマスの一覧．cells があればそれを拡大縮小し，
+    /// なければ layout の grid から作る
+    pub fn cells(&self, base: &Layout, width: i32, height: i32) -> error::Result<Vec<Cell>> {
+        let cells = if self.cells.is_empty() {
+            self.grid_cells(&base.scaled_to(width, height).grid)
+        } else {
+            let sx = width as f64 / base.ref_width as f64;
+            let sy = height as f64 / base.ref_height as f64;
+            let x = |v: i32| (v as f64 * sx).round() as i32;
+            let y = |v: i32| (v as f64 * sy).round() as i32;
+            self.cells
+                .iter()
+                .map(|CellDef { rect, x: cx, y: cy }| Cell {
+                    rect: Sq {
+                        x: x(rect.x),
+                        y: y(rect.y),
+                        w: x(rect.w).max(1),
+                        h: y(rect.h).max(1),
+                    },
+                    loc: GridLoc { x: *cx, y: *cy },
+                })
+                .collect()
+        };
+        if cells.is_empty() {
+            return Err(Error::InvalidScale("no cells".to_string()));
+        }
+        if let Some(cell) = cells.iter().find(|c| {
+            c.rect.x < 0
+                || c.rect.y < 0
+                || c.rect.x + c.rect.w > width
+                || c.rect.y + c.rect.h > height
+        }) {
+            return Err(Error::InvalidScale(format!(
+                "cell {:?} at {:?} is outside the {width}x{height} frame",
+                cell.loc, cell.rect
+            )));
+        }
+        Ok(cells)
+    }
+}
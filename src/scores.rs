@@ -0,0 +1,112 @@
+use std::io::Write;
+
+use clap::ValueEnum;
+use opencv::prelude::*;
+use opencv::videoio::VideoCapture;
+
+use crate::error;
+use crate::layout::Layout;
+use crate::match_ncc::NccMatcher;
+use crate::matcher::Method;
+use crate::timing::{current_sec, video_fps, Timing};
+
+/// 各フレームの，各 matcher の生のスコア．閾値を決めるために見る
+pub struct Scores {
+    /// matcher の名前．列の名前になる
+    pub names: Vec<&'static str>,
+    /// scores[i][j] は i フレーム目の names[j] のスコア
+    pub scores: Vec<Vec<f64>>,
+    pub timing: Timing,
+}
+
+impl Scores {
+    /// frame,sec,{names...}
+    pub fn report_csv<W: Write>(&self, mut paper: &mut W) -> std::io::Result<()> {
+        writeln!(&mut paper, "frame,sec,{}", self.names.join(","))?;
+        for (frame, row) in self.scores.iter().enumerate() {
+            let sec = self.timing.sec(frame);
+            let row: Vec<String> = row.iter().map(|s| s.to_string()).collect();
+            writeln!(&mut paper, "{frame},{sec},{}", row.join(","))?;
+        }
+        paper.flush()
+    }
+
+    /// j 番目の matcher のスコアだけ
+    pub fn column(&self, j: usize) -> Vec<f64> {
+        self.scores.iter().map(|row| row[j]).collect()
+    }
+}
+
+/// 動画を一度だけ読んで，全部の matcher のスコアを出す．
+/// ncc は相関が一番高かったところの ROI からのずれも ncc_dx, ncc_dy として出す
+pub fn do_scores(
+    vc: &mut VideoCapture,
+    layout: &Layout,
+    tmpl_file: &str,
+    fps: Option<f64>,
+) -> error::Result<Scores> {
+    // ncc はずれも欲しいので別に扱う
+    let methods: Vec<Method> = Method::value_variants()
+        .iter()
+        .copied()
+        .filter(|&method| method != Method::Ncc)
+        .collect();
+    let ncc = NccMatcher::from_file(tmpl_file, layout)?;
+    let matchers = methods
+        .iter()
+        .map(|method| method.load(tmpl_file, layout))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut scores = vec![];
+    let mut stamps = vec![];
+    let mut frame = Mat::default();
+    while let Ok(b) = vc.read(&mut frame)
+        && b
+    {
+        let mut row = matchers
+            .iter()
+            .map(|matcher| matcher.score(&frame))
+            .collect::<opencv::Result<Vec<_>>>()?;
+        let found = ncc.find(&frame)?;
+        row.extend([
+            1.0 - found.peak,
+            found.offset.x as f64,
+            found.offset.y as f64,
+        ]);
+        scores.push(row);
+        stamps.push(current_sec(vc)?);
+    }
+    let mut names: Vec<&'static str> = methods.iter().map(|method| method.name()).collect();
+    names.extend(["ncc", "ncc_dx", "ncc_dy"]);
+    Ok(Scores {
+        names,
+        scores,
+        timing: Timing::new(stamps, fps, video_fps(vc)),
+    })
+}
+
+/// テキストのヒストグラム．bins 個に等分して，各行に範囲と数と棒を書く．
+/// 評定画面とそれ以外で数が大きく違うので，棒の長さは数の対数にしている
+pub fn histogram(scores: &[f64], bins: usize) -> String {
+    const WIDTH: f64 = 60.0;
+    let min = scores.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = scores.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if scores.is_empty() || bins == 0 {
+        return String::new();
+    }
+    let width = ((max - min) / bins as f64).max(f64::EPSILON);
+    let mut counts = vec![0usize; bins];
+    for &s in scores {
+        let bin = (((s - min) / width) as usize).min(bins - 1);
+        counts[bin] += 1;
+    }
+    let scale = WIDTH / (1.0 + *counts.iter().max().unwrap_or(&0) as f64).ln();
+    counts
+        .iter()
+        .enumerate()
+        .map(|(i, &count)| {
+            let from = min + width * i as f64;
+            let bar = "#".repeat(((1.0 + count as f64).ln() * scale).round() as usize);
+            format!("{from:>14.4} - {:<14.4} {count:>8} {bar}\n", from + width)
+        })
+        .collect()
+}
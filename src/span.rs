@@ -1,9 +1,14 @@
 use std::fmt::Debug;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
 
 use crate::base::Frame;
+use crate::error::{self, ParseError};
+use crate::timing::Timing;
 
-pub trait FromLine {
-    fn from_line(line: &str) -> Self;
+/// CSV の1行から読む．Err はどこがおかしいかの説明
+pub trait FromLine: Sized {
+    fn from_line(line: &str) -> Result<Self, String>;
 }
 
 #[derive(Debug)]
@@ -15,21 +20,33 @@ pub struct Span<T: Debug + Clone> {
 }
 
 impl<T: Debug + Clone> Span<T> {
+    /// フレーム数．to も区間に入るので `to - from + 1`
     pub fn dur(&self) -> Frame {
-        self.to - self.from
+        self.to + 1 - self.from
+    }
+}
+
+/// 値の無い区間は `i,from,to,...` の行から読む（i と4列目から後は読まない）
+impl FromLine for Span<()> {
+    fn from_line(line: &str) -> Result<Self, String> {
+        let dat: Vec<&str> = line.split(",").collect();
+        if dat.len() < 3 {
+            return Err(format!("expected at least 3 columns: {line:?}"));
+        }
+        let parse = |s: &str| s.parse().map_err(|e| format!("{s:?}: {e}"));
+        Ok(Span {
+            val: (),
+            from: parse(dat[1])?,
+            to: parse(dat[2])?,
+        })
     }
 }
 
 #[derive(Debug)]
+/// 区間の列．どの区間も to はその区間の最後のフレーム
 pub struct Spans<T: Debug + Clone> {
     dat: Vec<Span<T>>,
 }
-// Does this make sense?
-// impl AsRef<Span<()>> for SimpleSpan {
-//     fn as_ref(&self) -> &Span<()> {
-//         &self.0
-//     }
-// }
 
 impl<T> Spans<T>
 where
@@ -45,6 +62,69 @@ where
     pub fn len(&self) -> usize {
         self.dat.len()
     }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Span<T>> {
+        self.dat.iter()
+    }
+
+    /// i 番目 (CSV の i 列と同じく 1 から) の区間の (from, to)
+    pub fn span(&self, i: usize) -> Option<(Frame, Frame)> {
+        let span = self.dat.get(i.checked_sub(1)?)?;
+        Some((span.from, span.to))
+    }
+
+    /// 1列目の名前が head で，区間ごとに first(添字, 値) を1列目にした
+    /// `{head},from,to,from_sec,to_sec,dur_frames,dur_seconds` を sep で区切って書く
+    fn write_table<W: Write>(
+        &self,
+        mut paper: &mut W,
+        timing: &Timing,
+        head: &str,
+        sep: &str,
+        first: impl Fn(usize, &T) -> String,
+    ) -> std::io::Result<()> {
+        writeln!(
+            &mut paper,
+            "{head}{sep}from{sep}to{sep}from_sec{sep}to_sec{sep}dur_frames{sep}dur_seconds"
+        )?;
+        for (i, span) in self.dat.iter().enumerate() {
+            let key = first(i, &span.val);
+            let (from, to) = (span.from, span.to);
+            let from_sec = timing.sec(from);
+            // 最後のフレームが終わるのは次のフレームの時刻
+            let to_sec = timing.sec(to + 1);
+            let dur_frames = span.dur();
+            let dur_seconds = to_sec - from_sec;
+            writeln!(
+                &mut paper,
+                "{key}{sep}{from}{sep}{to}{sep}{from_sec}{sep}{to_sec}{sep}{dur_frames}{sep}{dur_seconds}"
+            )?;
+        }
+        Ok(())
+    }
+
+    /// state,from,to,from_sec,to_sec,dur_frames,dur_seconds．
+    /// to はその区間の最後のフレーム．state 列は label で文字列にする
+    pub fn report<W: Write>(
+        &self,
+        paper: &mut W,
+        timing: &Timing,
+        label: impl Fn(&T) -> String,
+    ) -> std::io::Result<()> {
+        self.write_table(paper, timing, "state", ",", |_, val| label(val))
+    }
+
+    /// [Spans::report] の state の代わりに，1 から数えた区間の番号 i を1列目にする．
+    /// sep が無ければ `,`
+    pub fn report_numbered<W: Write>(
+        &self,
+        paper: &mut W,
+        timing: &Timing,
+        sep: Option<&str>,
+    ) -> std::io::Result<()> {
+        let sep = sep.unwrap_or(",");
+        self.write_table(paper, timing, "i", sep, |i, _| (i + 1).to_string())
+    }
 }
 
 impl<T> Spans<T>
@@ -52,8 +132,46 @@ where
     T: Debug + Clone + Copy + PartialEq + Eq,
 {
     /// (frame, val) のvec を，連続するものをつなげて
-    /// Spans にする
+    /// Spans にする．フレームが飛んでいたり val が変わったりしたら次の区間．
+    /// to はその区間の最後のフレーム
     pub fn from_frames(data: &[(Frame, T)]) -> Self {
-        Spans { dat: vec![] }
+        let mut dat: Vec<Span<T>> = vec![];
+        for &(frame, val) in data {
+            match dat.last_mut() {
+                Some(last) if last.val == val && last.to + 1 == frame => last.to = frame,
+                _ => dat.push(Span {
+                    val,
+                    from: frame,
+                    to: frame,
+                }),
+            }
+        }
+        Spans { dat }
+    }
+}
+
+impl Spans<()> {
+    /// フレームごとの判定から，true の続くところを区間にする
+    pub fn from_bools(bools: &[bool]) -> Self {
+        let frames: Vec<(Frame, ())> = bools
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| **b)
+            .map(|(i, _)| (i, ()))
+            .collect();
+        Spans::from_frames(&frames)
+    }
+
+    /// [Spans::report_numbered] で書いたファイルから読む
+    pub fn from_file(f: &str) -> error::Result<Self> {
+        let reader = BufReader::new(File::open(f)?);
+        let mut dat = vec![];
+        // 1行目は header
+        for (i, line) in reader.lines().enumerate().skip(1) {
+            let line = line?;
+            let span = Span::from_line(&line).map_err(|msg| ParseError { line: i + 1, msg })?;
+            dat.push(span);
+        }
+        Ok(Spans { dat })
     }
 }
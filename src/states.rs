@@ -0,0 +1,232 @@
+use std::fs;
+use std::io::Write;
+
+use opencv::prelude::*;
+use opencv::videoio::VideoCapture;
+use serde::{Deserialize, Serialize};
+
+use crate::base::{Frame, Sq};
+use crate::decode::{viterbi, ModelDef, TransitionModel};
+use crate::elog;
+use crate::error::{self, Error};
+use crate::layout::Layout;
+use crate::matcher::{FrameMatcher, Method};
+use crate::span::Spans;
+use crate::template::{Provenance, Template};
+use crate::timing::{current_sec, video_fps, Timing};
+
+#[derive(Debug)]
+/// 画面の一覧のファイルが読めない，あるいは中身が TOML として読めない
+pub enum ScreensError {
+    IoError(std::io::Error),
+    ParseError(toml::de::Error),
+}
+
+impl From<std::io::Error> for ScreensError {
+    fn from(err: std::io::Error) -> ScreensError {
+        ScreensError::IoError(err)
+    }
+}
+
+impl From<toml::de::Error> for ScreensError {
+    fn from(err: toml::de::Error) -> ScreensError {
+        ScreensError::ParseError(err)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// 見分けたい画面一つ．評定画面，注視点，刺激，教示，休憩など
+pub struct ScreenDef {
+    /// 出力の state 列に出る名前
+    pub name: String,
+    /// 参照画像の名前 (`data/{template}.png`)
+    pub template: String,
+    /// どこを見るか．座標は layout の ref_width x ref_height の画面でのもの．無ければ layout の va_roi
+    pub roi: Option<Sq>,
+    #[serde(default)]
+    pub method: Method,
+    /// 無ければ method の既定の閾値
+    pub threshold: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// 画面の一覧．TOML では
+///
+/// ```toml
+/// [[screen]]
+/// name = "fixation"
+/// template = "fixation"
+/// roi = { x = 1390, y = 850, w = 100, h = 100 }
+///
+/// [[screen]]
+/// name = "va"
+/// template = "va_roi"
+/// method = "ncc"
+/// ```
+///
+/// `states --decode` で使う遷移のモデルも `[model]` に書ける ([ModelDef])
+pub struct Screens {
+    #[serde(rename = "screen")]
+    pub screens: Vec<ScreenDef>,
+    #[serde(default)]
+    pub model: Option<ModelDef>,
+}
+
+impl Screens {
+    pub fn from_file(f: &str) -> Result<Self, ScreensError> {
+        let content = fs::read_to_string(f)?;
+        Ok(toml::from_str(&content)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// フレームごとの，どの画面だったか
+pub enum State {
+    /// どの画面とも一致しない
+    Unknown,
+    /// screens[i] の画面
+    Screen(usize),
+}
+
+/// 画面ごとの matcher をまとめて持って，フレームの State を決める
+pub struct StateDetector {
+    names: Vec<String>,
+    matchers: Vec<Box<dyn FrameMatcher>>,
+    thresholds: Vec<f64>,
+}
+
+impl StateDetector {
+    /// base は動画の解像度に合わせる前の layout．
+    /// 各画面の roi を差し替えてから width x height に合わせる
+    pub fn new(screens: &Screens, base: &Layout, width: i32, height: i32) -> error::Result<Self> {
+        let mut names = vec![];
+        let mut matchers = vec![];
+        let mut thresholds = vec![];
+        for screen in &screens.screens {
+            let layout = Layout {
+                va_roi: screen.roi.unwrap_or(base.va_roi),
+                ..*base
+            }
+            .scaled_to(width, height);
+            let matcher = screen
+                .method
+                .load(&Template::new(&screen.template).image_file(), &layout)?;
+            thresholds.push(screen.threshold.unwrap_or(matcher.default_threshold()));
+            names.push(screen.name.clone());
+            matchers.push(matcher);
+        }
+        Ok(StateDetector {
+            names,
+            matchers,
+            thresholds,
+        })
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn label(&self, state: &State) -> &str {
+        match state {
+            State::Unknown => "unknown",
+            State::Screen(i) => &self.names[*i],
+        }
+    }
+
+    /// 各画面の (スコア / 閾値)．1 未満ならその画面に一致している
+    pub fn ratios(&self, frame: &Mat) -> opencv::Result<Vec<f64>> {
+        self.matchers
+            .iter()
+            .zip(&self.thresholds)
+            .map(|(matcher, &threshold)| Ok(matcher.score(frame)? / threshold))
+            .collect()
+    }
+
+    /// 閾値未満の画面のうち，スコアが閾値に比べて一番小さいもの．無ければ Unknown
+    pub fn raw_state(ratios: &[f64]) -> State {
+        let mut best = (State::Unknown, 1.0);
+        for (i, &ratio) in ratios.iter().enumerate() {
+            if ratio < best.1 {
+                best = (State::Screen(i), ratio);
+            }
+        }
+        best.0
+    }
+
+    pub fn state_of(&self, frame: &Mat) -> opencv::Result<State> {
+        Ok(StateDetector::raw_state(&self.ratios(frame)?))
+    }
+
+    /// 動画を最後まで読んで，フレームごとの [StateDetector::ratios] と時刻
+    pub fn detect(&self, vc: &mut VideoCapture) -> opencv::Result<(Vec<Vec<f64>>, Vec<f64>)> {
+        let mut ratios = vec![];
+        let mut stamps = vec![];
+        let mut frame = Mat::default();
+        while let Ok(b) = vc.read(&mut frame)
+            && b
+        {
+            ratios.push(self.ratios(&frame)?);
+            stamps.push(current_sec(vc)?);
+        }
+        Ok((ratios, stamps))
+    }
+}
+
+/// 画面ごとの区間の表 `{file}.states.csv` を書く．
+/// decode なら各フレームを独立に閾値で判定せず，遷移のモデル ([Screens::model]) の下で
+/// Viterbi で一番ありそうな列を求める．そのときは閾値での判定と食い違ったフレームを
+/// `{file}.states.flags.csv` に書く
+pub fn do_states(
+    vc: &mut VideoCapture,
+    file_name: &str,
+    screens: &Screens,
+    base: &Layout,
+    (width, height): (i32, i32),
+    decode: bool,
+    fps: Option<f64>,
+) -> error::Result<()> {
+    let detector = StateDetector::new(screens, base, width, height)?;
+    let (ratios, stamps) = detector.detect(vc)?;
+    let timing = Timing::new(stamps, fps, video_fps(vc));
+    let raw: Vec<State> = ratios.iter().map(|r| StateDetector::raw_state(r)).collect();
+    let states = if decode {
+        let def = screens.model.clone().unwrap_or_default();
+        let model = TransitionModel::new(&def, detector.names()).map_err(Error::InvalidScreens)?;
+        let decoded = viterbi(&ratios, &model);
+        let outname = format!("{file_name}.states.flags.csv");
+        let mut f = std::io::BufWriter::new(fs::File::create(&outname)?);
+        writeln!(&mut f, "frame,sec,raw,decoded")?;
+        let mut disagreements = 0;
+        for (frame, (r, d)) in raw.iter().zip(&decoded).enumerate() {
+            if r != d {
+                disagreements += 1;
+                let sec = timing.sec(frame);
+                let (r, d) = (detector.label(r), detector.label(d));
+                writeln!(&mut f, "{frame},{sec},{r},{d}")?;
+            }
+        }
+        f.flush()?;
+        elog!(
+            "states: decoded state differs from the raw threshold in {disagreements} of {} frames",
+            raw.len()
+        );
+        decoded
+    } else {
+        raw
+    };
+    let frames: Vec<(Frame, State)> = states.into_iter().enumerate().collect();
+    let spans = Spans::from_frames(&frames);
+    let outname = format!("{file_name}.states.csv");
+    let mut f = std::io::BufWriter::new(fs::File::create(&outname)?);
+    spans.report(&mut f, &timing, |state| detector.label(state).to_string())?;
+    f.flush()?;
+    let templates: Vec<Template> = screens
+        .screens
+        .iter()
+        .map(|screen| Template::new(&screen.template))
+        .collect();
+    let mut provenance = Provenance::load(file_name);
+    provenance.record(&outname, &templates);
+    provenance.save(file_name)?;
+    Ok(())
+}
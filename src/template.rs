@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::base::{Frame, Sq};
+use crate::consts;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// 参照画像をどう作ったか．`{name}.meta.toml` に保存する
+pub struct TemplateMeta {
+    /// 切り出した動画
+    pub source_video: String,
+    /// 使ったフレーム．複数ならピクセルごとの中央値
+    pub frames: Vec<Frame>,
+    /// frames の時刻 (秒)
+    pub secs: Vec<f64>,
+    /// 切り出した長方形 (動画の解像度での座標)
+    pub roi: Sq,
+    /// 2値化の閾値
+    pub bw_threshold: f64,
+    /// 作った日時 (UTC, ISO 8601)
+    pub created: String,
+    /// 参照画像のファイルの FNV-1a (64bit) ハッシュ
+    pub hash: String,
+}
+
+#[derive(Debug, Clone)]
+/// 名前のついた参照画像．[consts::TEMPLATE_DIR] の下の
+/// `{name}.png` (本体)，`{name}.mask.png`，`{name}.stability.png`，`{name}.meta.toml` からなる
+pub struct Template {
+    pub name: String,
+}
+
+impl Template {
+    pub fn new(name: &str) -> Self {
+        Template {
+            name: name.to_string(),
+        }
+    }
+
+    /// 参照画像のファイル名．マスクなどのファイル名はここから作る
+    pub fn image_file(&self) -> String {
+        format!("{}/{}.png", consts::TEMPLATE_DIR, self.name)
+    }
+
+    pub fn meta_file(&self) -> String {
+        format!("{}/{}.meta.toml", consts::TEMPLATE_DIR, self.name)
+    }
+
+    /// メタデータ．無いか読めなければ None
+    pub fn meta(&self) -> Option<TemplateMeta> {
+        let content = fs::read_to_string(self.meta_file()).ok()?;
+        toml::from_str(&content).ok()
+    }
+
+    pub fn write_meta(&self, meta: &TemplateMeta) -> io::Result<()> {
+        let toml = toml::to_string(meta).expect("TemplateMeta is always serializable");
+        fs::write(self.meta_file(), toml)
+    }
+
+    /// 結果のファイルに残す，どの参照画像を使ったか
+    pub fn provenance(&self) -> TemplateRecord {
+        TemplateRecord {
+            name: self.name.clone(),
+            meta: self.meta(),
+        }
+    }
+
+    /// 動画 file_name の結果 outname をこの参照画像から作ったことを，
+    /// `{file_name}.provenance.toml` に書き足す．CSV そのものには何も足さない
+    pub fn write_provenance(&self, file_name: &str, outname: &str) -> io::Result<()> {
+        let mut provenance = Provenance::load(file_name);
+        provenance.record(outname, std::slice::from_ref(self));
+        provenance.save(file_name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// 使った参照画像の名前と，その時点のメタデータ（無ければ無し）
+pub struct TemplateRecord {
+    pub name: String,
+    pub meta: Option<TemplateMeta>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// 一つの結果のファイルをどう作ったか
+pub struct OutputProvenance {
+    /// 書いた日時 (UTC, ISO 8601)
+    pub written: String,
+    pub templates: Vec<TemplateRecord>,
+    /// `process --auto-threshold` で選んで使った閾値
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_threshold: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+/// 動画ごとの `{file}.provenance.toml`．結果のファイル名 (`video.mov.clicks.csv` など) ごとに，
+/// どの参照画像から作ったか．同じ結果を書き直せば上書きする
+pub struct Provenance {
+    #[serde(default)]
+    pub outputs: BTreeMap<String, OutputProvenance>,
+}
+
+impl Provenance {
+    pub fn file(file_name: &str) -> String {
+        format!("{file_name}.provenance.toml")
+    }
+
+    /// 今までの記録．無いか読めなければ空から
+    pub fn load(file_name: &str) -> Self {
+        fs::read_to_string(Provenance::file(file_name))
+            .ok()
+            .and_then(|content| toml::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    /// outname を templates から作ったと記録する．キーは outname のファイル名の部分
+    pub fn record(&mut self, outname: &str, templates: &[Template]) -> &mut OutputProvenance {
+        let key = Path::new(outname)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| outname.to_string());
+        let entry = OutputProvenance {
+            written: now_iso8601(),
+            templates: templates.iter().map(Template::provenance).collect(),
+            auto_threshold: None,
+        };
+        self.outputs.insert(key.clone(), entry);
+        self.outputs.get_mut(&key).unwrap()
+    }
+
+    pub fn save(&self, file_name: &str) -> io::Result<()> {
+        let toml = toml::to_string(self).expect("Provenance is always serializable");
+        fs::write(Provenance::file(file_name), toml)
+    }
+}
+
+/// [consts::TEMPLATE_DIR] にある参照画像の一覧（名前順）
+pub fn list_templates() -> io::Result<Vec<Template>> {
+    let mut names = vec![];
+    for entry in fs::read_dir(consts::TEMPLATE_DIR)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        // マスクと安定度の画像は本体ではない
+        if let Some(stem) = name.strip_suffix(".png")
+            && !stem.ends_with(".mask")
+            && !stem.ends_with(".stability")
+        {
+            names.push(stem.to_string());
+        }
+    }
+    names.sort();
+    Ok(names.iter().map(|name| Template::new(name)).collect())
+}
+
+/// FNV-1a (64bit) で f の中身のハッシュを取る
+pub fn file_hash(f: &Path) -> io::Result<String> {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in &fs::read(f)? {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    Ok(format!("{hash:016x}"))
+}
+
+/// 今の日時 (UTC) を `2025-01-31T12:34:56Z` の形で
+pub fn now_iso8601() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (days, rest) = ((secs / 86400) as i64, secs % 86400);
+    // 1970-01-01 からの日数を年月日にする (Howard Hinnant の civil_from_days)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rest / 3600,
+        rest / 60 % 60,
+        rest % 60
+    )
+}
@@ -0,0 +1,110 @@
+/// 大津の方法でヒストグラムを作るときの区切りの数
+const OTSU_BINS: usize = 256;
+/// 分離度 (クラス間分散 / 全分散) がこれ未満なら，二つの山に分かれていないとみなす
+const MIN_SEPARABILITY: f64 = 0.8;
+
+#[derive(Debug, Clone, Copy)]
+/// 選んだ閾値と，それがどのくらいはっきりしているか
+pub struct AutoThreshold {
+    /// 下の山の一番大きいスコアと上の山の一番小さいスコアの真ん中
+    pub threshold: f64,
+    /// 下の山の一番大きいスコアと上の山の一番小さいスコアの差．谷の幅
+    pub margin: f64,
+    /// クラス間分散 / 全分散．1 に近いほどきれいに分かれている
+    pub separability: f64,
+    /// 閾値より下（一致している側）のフレーム数
+    pub below: usize,
+    /// 閾値より上のフレーム数
+    pub above: usize,
+}
+
+impl AutoThreshold {
+    pub fn is_bimodal(&self) -> bool {
+        self.separability >= MIN_SEPARABILITY && self.margin > 0.0
+    }
+}
+
+/// 評定画面のフレームとそれ以外のフレームでスコアが二つの山に分かれるはずなので，
+/// 大津の方法で scores を二つに分けて，その谷に閾値を置く．全部同じ値（か空）なら None
+pub fn otsu(scores: &[f64]) -> Option<AutoThreshold> {
+    let min = scores.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = scores.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if scores.is_empty() || max <= min {
+        return None;
+    }
+    let width = (max - min) / OTSU_BINS as f64;
+    let bin_of = |s: f64| (((s - min) / width) as usize).min(OTSU_BINS - 1);
+    let mut counts = vec![0usize; OTSU_BINS];
+    let mut sums = vec![0.0; OTSU_BINS];
+    for &s in scores {
+        let bin = bin_of(s);
+        counts[bin] += 1;
+        sums[bin] += s;
+    }
+    let total = scores.len() as f64;
+    let total_sum: f64 = sums.iter().sum();
+    let mean = total_sum / total;
+    let total_var = scores.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / total;
+
+    // (クラス間分散, 区切り)．bin が区切りより小さいものが下のクラス
+    let mut best = (0.0, 0);
+    let (mut n0, mut s0) = (0.0, 0.0);
+    for k in 0..OTSU_BINS - 1 {
+        n0 += counts[k] as f64;
+        s0 += sums[k];
+        let n1 = total - n0;
+        if n0 == 0.0 || n1 == 0.0 {
+            continue;
+        }
+        let between = n0 * n1 * (s0 / n0 - (total_sum - s0) / n1).powi(2) / (total * total);
+        if between > best.0 {
+            best = (between, k + 1);
+        }
+    }
+    let (between, split) = best;
+    if split == 0 {
+        return None;
+    }
+    let (below, above): (Vec<f64>, Vec<f64>) =
+        scores.iter().copied().partition(|&s| bin_of(s) < split);
+    let below_max = below.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let above_min = above.iter().copied().fold(f64::INFINITY, f64::min);
+    Some(AutoThreshold {
+        threshold: (below_max + above_min) / 2.0,
+        margin: above_min - below_max,
+        separability: between / total_var,
+        below: below.len(),
+        above: above.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn otsu_splits_two_clusters_in_the_gap() {
+        let mut scores: Vec<f64> = (0..50).map(|i| 0.08 + 0.04 * i as f64 / 49.0).collect();
+        scores.extend((0..30).map(|i| 0.88 + 0.04 * i as f64 / 29.0));
+        let auto = otsu(&scores).unwrap();
+        assert!((auto.threshold - 0.5).abs() < 1e-9);
+        assert!((auto.margin - 0.76).abs() < 1e-9);
+        assert_eq!((auto.below, auto.above), (50, 30));
+        assert!(auto.is_bimodal());
+    }
+
+    #[test]
+    fn otsu_flags_a_single_cluster() {
+        // 一様に広がったスコアは分離度が 0.75 くらいにしかならない
+        let scores: Vec<f64> = (0..100).map(|i| i as f64 / 99.0).collect();
+        let auto = otsu(&scores).unwrap();
+        assert!(auto.separability < MIN_SEPARABILITY);
+        assert!(!auto.is_bimodal());
+    }
+
+    #[test]
+    fn otsu_needs_spread() {
+        assert!(otsu(&[]).is_none());
+        assert!(otsu(&[0.3; 10]).is_none());
+    }
+}
@@ -0,0 +1,70 @@
+use opencv::prelude::*;
+use opencv::videoio::{VideoCapture, CAP_PROP_FPS, CAP_PROP_POS_MSEC};
+
+use crate::base::Frame;
+use crate::consts;
+use crate::elog;
+
+#[derive(Debug, Clone)]
+/// フレーム番号から秒への変換．
+/// 画面収録は可変フレームレートのことが多いので，普段は読みながら記録した
+/// 各フレームの時刻を使う．--fps が与えられたときだけ frame / fps にする
+pub enum Timing {
+    Fps(f64),
+    /// i フレーム目の時刻 (秒)
+    Stamps(Vec<f64>),
+}
+
+/// 今読んだフレームの時刻 (秒)
+pub fn current_sec(vc: &VideoCapture) -> opencv::Result<f64> {
+    Ok(vc.get(CAP_PROP_POS_MSEC)? / 1000.0)
+}
+
+/// 動画の持ってる FPS．取れなければ [consts::DEFAULT_FPS]
+pub fn video_fps(vc: &VideoCapture) -> f64 {
+    match vc.get(CAP_PROP_FPS) {
+        Ok(fps) if fps > 0.0 => fps,
+        _ => consts::DEFAULT_FPS,
+    }
+}
+
+impl Timing {
+    /// 記録した時刻を使う．ただし時刻が取れていないっぽい
+    /// （全部 0 だったり，戻ったりする）ときは fallback_fps を使う
+    pub fn from_stamps(stamps: Vec<f64>, fallback_fps: f64) -> Self {
+        let is_sane = stamps.windows(2).all(|w| w[0] <= w[1])
+            && (stamps.len() < 2 || stamps[stamps.len() - 1] > 0.0);
+        if is_sane {
+            Timing::Stamps(stamps)
+        } else {
+            elog!("timing:warning: timestamps look broken; using {fallback_fps} fps instead");
+            Timing::Fps(fallback_fps)
+        }
+    }
+
+    /// --fps があればそれを，なければ記録した時刻を使う
+    pub fn new(stamps: Vec<f64>, fps_override: Option<f64>, fallback_fps: f64) -> Self {
+        match fps_override {
+            Some(fps) => Timing::Fps(fps),
+            None => Timing::from_stamps(stamps, fallback_fps),
+        }
+    }
+
+    /// frame フレーム目の時刻 (秒)．
+    /// 記録より後ろのフレーム（区間の終わりが動画の終わりのときなど）は，
+    /// 平均のフレーム間隔で延ばす
+    pub fn sec(&self, frame: Frame) -> f64 {
+        match self {
+            Timing::Fps(fps) => frame as f64 / fps,
+            Timing::Stamps(stamps) => match stamps.get(frame) {
+                Some(&sec) => sec,
+                None if stamps.len() < 2 => frame as f64 / consts::DEFAULT_FPS,
+                None => {
+                    let last = stamps.len() - 1;
+                    let interval = (stamps[last] - stamps[0]) / last as f64;
+                    stamps[last] + (frame - last) as f64 * interval
+                }
+            },
+        }
+    }
+}